@@ -26,7 +26,7 @@ export type UserSignup = {
 export type UserData = {
   username: string,
   name: string,
-  address: string,
+  address: FilledAddress,
   location: [number, number], // [lat, long]
   user_type: { Volunteer: string[] } | { Senior: string | null }, // Volunteer contains a list of every request ID they've accepted, Senior contains the request ID of the request they've made
 }
@@ -44,9 +44,6 @@ export type FilledAddress = {
   state: State,
   zip: String
 }
-export enum LonLatRequestError {
-  unknownError
-}
 export type LoginParameters = {
   username: String,
   password: String
@@ -74,7 +71,7 @@ export type SelfRequestResult = {
   state: "Pending" | { AcceptedBy: User } | { MarkedCompletedBy: User }
 }
 export type HelpRequest = {
-  picture: string,
+  picture: string, // base64 JPEG, uploaded before the request is made
   notes: string
 }
 export type HelpRequestParsed = {
@@ -119,12 +116,6 @@ export const generateSuccessDialog = (msg: string): void => {
     message: msg
   });
 }
-export const concatAddress = async (address: FilledAddress) : Promise<String | void> => {
-  return `${address.line1?.trim()}
-${address.line2?.trim()}
-${address.city?.trim()} ${address.state?.trim()} ${address.zip?.trim()}
-United States of America`
-}
 export const checkIfAddressFilledIn = (address: Address) : FilledAddress | Promise<void> => {
   if (!address.city || !address.line1 || !address.state || !address.zip) {
     return invalidAddressAlert()
@@ -132,48 +123,15 @@ export const checkIfAddressFilledIn = (address: Address) : FilledAddress | Promi
   return address as FilledAddress;
 }
 
-const addressToLonLat = async (address: FilledAddress) : Promise<[Number, Number] | LonLatRequestError> => {
-  console.log("running address -> lon lat request")
-  console.error(stateList[address.state])
-  
-  const res = await fetch(
-    // `https://nominatim.openstreetmap.org/search?street=${address.line1}&city=${address.city}&state=${address.state}&postalcode=${address.zip}&format=geocodejson&countrycodes=us`, 
-    // `https://nominatim.openstreetmap.org/search?q=${address.line1}, ${address.city}, ${address.state}&format=geocodejson&countrycodes=us`, 
-    `https://nominatim.openstreetmap.org/search?addressdetails=1&q=${address.line1}, ${address.city}, ${stateList[address.state]}&format=jsonv2&countrycodes=us&limit=1`, 
-    {
-    method: "GET",
-    headers: {
-      'Content-Type': 'application/x-www-form-urlencoded',
-    }
-    // new URLSearchParams({
-    //   street: address.line1,
-    //   city: address.city,
-    //   state: address.state,
-    //   country: "The United States",
-    //   postalcode: address.zip,
-    //   format: "geocodejson",
-    //   countrycodes: "us"
-    // } as Record<symbol, String>)
-  })
-  console.log(`https://nominatim.openstreetmap.org/search?addressdetails=1&q=${address.line1}, ${address.city}, ${address.state}&format=jsonv2&countrycodes=us&limit=1`)
-  if (!res.ok) return LonLatRequestError.unknownError;
-  const json = await res.json()
-  const queryData = json?.[0]
-  const lonLat = [queryData?.lat, queryData?.lon]
-  if (!lonLat[0] || !lonLat[1]) return LonLatRequestError.unknownError;
-  console.log(lonLat)
-  // @ts-expect-error
-  return lonLat.map(v=>Number(v)) as [Number, Number];
-}
-
 // TODO: Implement
 const askUserToCheckIfAddressIsCorrect = () => {
   
 }
 
-const parseRequestImage = async (json: {picture:string}): Promise<any> => {
+const parseRequestImage = async (json: {pictures: string[]}): Promise<any> => {
+  const [hash] = json.pictures ?? [];
   // @ts-expect-error
-  json.picture = await ImageSource.fromBase64(json);
+  json.picture = hash ? await ImageSource.fromUrl(`${serverURL}/pictures/${hash}`) : undefined;
   return json;
 }
 const getAuthorizationString = () : string => {
@@ -208,18 +166,16 @@ const apiFetchPost = async (endpoint: string, data: Object): Promise<any> => {
 
 export const createAccount = async (user: UserSignup) : Promise<LoginResult> => {
   // console.log(user);
-  const location = await addressToLonLat(user.address)
-  const addressString = await concatAddress(user.address)
-  if (location == LonLatRequestError.unknownError) return LoginResult.addressError;
+  // The server works out where the address is, and gives a 400 if it can't find it
   const res = await apiFetchPost(`create-account`, {
       username: user.username, 
       name: user.name,
-      address: addressString,
-      location,
+      address: user.address,
       userType: user.userType,
       password: user.password
   })
   if (res.status == 409) return LoginResult.usernameError;
+  if (res.status == 400) return LoginResult.addressError;
   if (!res.ok) return LoginResult.unknownError;
   ApplicationSettings.setString("AuthorizationString", await res.text());
   return LoginResult.success;
@@ -232,11 +188,22 @@ export const login = async (loginInfo: LoginParameters) : Promise<LoginResult> =
   ApplicationSettings.setString("AuthorizationString", await res.text());
   return LoginResult.success
 }
+export const uploadPicture = async (data: string, mimeType: string) : Promise<string> => {
+  const res = await apiFetchPost("upload-picture", {
+    authorization: getAuthorizationString(),
+    mimeType,
+    data
+  })
+  if (!res.ok) throw "Picture upload failed";
+  return res.json().hash
+}
 export const requestHelp = async (helpRequest: HelpRequest) : Promise<HelpRequestResult> => {
   // await console.log(JSON.stringify({authorization: getAuthorizationString()}))
+  const pictures = helpRequest.picture ? [await uploadPicture(helpRequest.picture, "image/jpeg")] : []
   const res = await apiFetchPost("request-help", {
     authorization: getAuthorizationString(),
-    ...helpRequest
+    pictures,
+    notes: helpRequest.notes
   })
   if (res.status == 405) return HelpRequestResult.notSenior
   if (!res.ok) return HelpRequestResult.unknownError
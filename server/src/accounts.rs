@@ -5,9 +5,14 @@ use serde_json::json;
 use warp::{body::bytes, hyper::Body, Filter, Rejection};
 
 use crate::{
+    address::Address,
     authorization::{authorize, create_token, hash_password},
+    blocking,
     db::Transactional,
     errors::Error,
+    extract_json,
+    geocoding::Geocoding,
+    spatial_index::VolunteerIndex,
     InfallibleDeserialize, Location, User, UserDB, UserType,
 };
 
@@ -22,8 +27,7 @@ enum UserTypeChoice {
 struct CreateAccountInfo {
     username: String,
     name: String,
-    address: String,
-    location: (f64, f64),
+    address: Address,
     user_type: UserTypeChoice,
     password: Secret<String>,
 }
@@ -35,44 +39,98 @@ struct LoginInfo {
     password: Secret<String>,
 }
 
+#[derive(Deserialize)]
+struct UpdateAddressInfo {
+    address: Address,
+}
+
 pub fn accounts_filters(
     db: &UserDB,
+    geocoding: &Geocoding,
+    volunteer_index: &VolunteerIndex,
 ) -> impl Filter<Extract = (Result<Body, Error>,), Error = Rejection> + Clone {
     let create_account_db = db.to_owned();
+    let create_account_geocoding = geocoding.to_owned();
+    let create_account_index = volunteer_index.to_owned();
     let create_account = warp::path!("api" / "create-account")
         .and(warp::body::json::<CreateAccountInfo>())
-        .map(move |create_account_info: CreateAccountInfo| {
+        .and_then(move |create_account_info: CreateAccountInfo| {
             let db = create_account_db.to_owned();
-            create_account(&db, create_account_info)
+            let geocoding = create_account_geocoding.to_owned();
+            let index = create_account_index.to_owned();
+            blocking(move || create_account(&db, &geocoding, &index, create_account_info))
         });
 
     let login_db = db.to_owned();
     let login = warp::path!("api" / "login")
         .and(warp::body::json::<LoginInfo>())
-        .map(move |login_info: LoginInfo| {
+        .and_then(move |login_info: LoginInfo| {
             let db = login_db.to_owned();
-            login(&db, login_info)
+            blocking(move || login(&db, login_info))
         });
 
     let account_info_db = db.to_owned();
     let account_info = warp::path!("api" / "user-data")
         .and(bytes())
-        .map(move |bytes| {
-            let username = authorize(&bytes)?;
+        .and_then(move |bytes| {
             let db = account_info_db.to_owned();
-            get_account_info(username, &db)
+            blocking(move || {
+                let username = authorize(&bytes)?;
+                get_account_info(username, &db)
+            })
         });
 
-    warp::post().and(create_account.or(login).unify().or(account_info).unify())
+    let update_address_db = db.to_owned();
+    let update_address_geocoding = geocoding.to_owned();
+    let update_address_index = volunteer_index.to_owned();
+    let update_address =
+        warp::path!("api" / "update-address")
+            .and(bytes())
+            .and_then(move |bytes| {
+                let db = update_address_db.to_owned();
+                let geocoding = update_address_geocoding.to_owned();
+                let index = update_address_index.to_owned();
+                blocking(move || {
+                    let username = authorize(&bytes)?;
+                    update_address(username, &db, &geocoding, &index, extract_json(&bytes)?)
+                })
+            });
+
+    warp::post().and(
+        create_account
+            .or(login)
+            .unify()
+            .or(account_info)
+            .unify()
+            .or(update_address)
+            .unify(),
+    )
 }
 
-fn create_account(db: &UserDB, create_account_info: CreateAccountInfo) -> Result<Body, Error> {
+fn create_account(
+    db: &UserDB,
+    geocoding: &Geocoding,
+    volunteer_index: &VolunteerIndex,
+    create_account_info: CreateAccountInfo,
+) -> Result<Body, Error> {
     debug!(
         "Attempting to create an account for {}",
         &create_account_info.username
     );
 
-    db.transaction(move |db| {
+    let address = create_account_info.address.validate()?;
+
+    // Checked again in the transaction, but taken usernames shouldn't cost a lookup
+    if db.get(&create_account_info.username)?.is_some() {
+        return Err(Error::UsernameAlreadyExists(create_account_info.username));
+    }
+
+    // Looked up before the transaction so a slow geocoder doesn't hold it open
+    let location = geocoding.locate(&address.to_string())?;
+
+    let is_volunteer = matches!(create_account_info.user_type, UserTypeChoice::Volunteer);
+
+    let token = db.transaction(|db| {
         if db.get(&create_account_info.username)?.is_some() {
             return Err(
                 Error::UsernameAlreadyExists(create_account_info.username.to_owned()).into(),
@@ -86,11 +144,11 @@ fn create_account(db: &UserDB, create_account_info: CreateAccountInfo) -> Result
         let user = User {
             username: create_account_info.username.to_owned(),
             name: create_account_info.name.to_owned(),
-            address: create_account_info.address.to_owned(),
-            location: create_account_info.location.into(),
+            address: address.to_owned(),
+            location,
             user_type: match create_account_info.user_type {
                 UserTypeChoice::Volunteer => UserType::Volunteer(Vec::new()),
-                UserTypeChoice::Senior => UserType::Senior(None),
+                UserTypeChoice::Senior => UserType::Senior(Vec::new()),
             },
             salt,
             password_hash,
@@ -103,9 +161,14 @@ fn create_account(db: &UserDB, create_account_info: CreateAccountInfo) -> Result
             &create_account_info.username
         );
 
-        Ok(Body::from(create_token(&create_account_info.username)?))
-    })
-    .map_err(|e| e.into())
+        Ok(create_token(&create_account_info.username)?)
+    })?;
+
+    if is_volunteer {
+        volunteer_index.insert(&create_account_info.username, location);
+    }
+
+    Ok(Body::from(token))
 }
 
 fn login(db: &UserDB, login_info: LoginInfo) -> Result<Body, Error> {
@@ -127,6 +190,46 @@ fn login(db: &UserDB, login_info: LoginInfo) -> Result<Body, Error> {
     Ok(Body::from(create_token(&login_info.username)?))
 }
 
+/// Changes where the user lives. Help requests they've already made stay where they were.
+fn update_address(
+    username: String,
+    db: &UserDB,
+    geocoding: &Geocoding,
+    volunteer_index: &VolunteerIndex,
+    info: UpdateAddressInfo,
+) -> Result<Body, Error> {
+    let address = info.address.validate()?;
+    let location = geocoding.locate(&address.to_string())?;
+
+    let (old_location, is_volunteer) = db.transaction(|db| {
+        let mut user = match db.get(&username)? {
+            Some(v) => v.to_original(),
+            None => return Err(Error::msg("The username doesn't exist in the database").into()),
+        };
+
+        let old_location = user.location;
+
+        user.address = address.to_owned();
+        user.location = location;
+
+        db.add(&username, &user)?;
+
+        Ok((
+            old_location,
+            matches!(user.user_type, UserType::Volunteer(_)),
+        ))
+    })?;
+
+    if is_volunteer {
+        volunteer_index.remove(&username, old_location);
+        volunteer_index.insert(&username, location);
+    }
+
+    info!("{username} updated their address");
+
+    Ok(Body::from("{}"))
+}
+
 fn get_account_info(username: String, db: &UserDB) -> Result<Body, Error> {
     let user = match db.get(&username)? {
         Some(v) => v,
@@ -142,7 +245,7 @@ fn get_account_info(username: String, db: &UserDB) -> Result<Body, Error> {
     Ok(Body::from(serde_json::to_string(&json!({
         "username": &*user.username,
         "name": &*user.name,
-        "address": &*user.address,
+        "address": user.address.to_json(),
         "location": <(f64, f64) as From<Location>>::from(user.location),
         "user_type": InfallibleDeserialize::<UserType>::deserialize(&user.user_type),
     }))?))
@@ -0,0 +1,180 @@
+use std::fmt::{self, Display, Formatter};
+
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::errors::Error;
+
+/// The postal codes of the states, DC and the territories
+const STATE_CODES: [&str; 56] = [
+    "AL", "AK", "AZ", "AR", "CA", "CO", "CT", "DE", "FL", "GA", "HI", "ID", "IL", "IN", "IA", "KS",
+    "KY", "LA", "ME", "MD", "MA", "MI", "MN", "MS", "MO", "MT", "NE", "NV", "NH", "NJ", "NM", "NY",
+    "NC", "ND", "OH", "OK", "OR", "PA", "RI", "SC", "SD", "TN", "TX", "UT", "VT", "VA", "WA", "WV",
+    "WI", "WY", "DC", "AS", "GU", "MP", "PR", "VI",
+];
+
+/// A US mailing address
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[repr(C)]
+pub struct Address {
+    line1: String,
+    /// Empty if there isn't one
+    #[serde(default)]
+    line2: String,
+    city: String,
+    /// The two letter postal code
+    state: String,
+    /// Five digits, optionally followed by a dash and four more
+    zip: String,
+}
+
+impl Address {
+    pub fn new(line1: String, line2: String, city: String, state: String, zip: String) -> Address {
+        Address {
+            line1,
+            line2,
+            city,
+            state,
+            zip,
+        }
+    }
+
+    /// Trims every part and makes the state uppercase, then checks that the parts that are needed are there and the state and ZIP code are real.
+    pub fn validate(mut self) -> Result<Address, Error> {
+        for part in [
+            &mut self.line1,
+            &mut self.line2,
+            &mut self.city,
+            &mut self.state,
+            &mut self.zip,
+        ] {
+            *part = part.trim().to_owned();
+        }
+
+        self.state.make_ascii_uppercase();
+
+        if self.line1.is_empty() || self.city.is_empty() {
+            return Err(Error::InvalidAddress(
+                "An address needs a street address and city",
+            ));
+        }
+
+        if !STATE_CODES.contains(&self.state.as_str()) {
+            return Err(Error::InvalidAddress(
+                "The state has to be a two letter postal code like MN",
+            ));
+        }
+
+        if !is_zip_code(&self.zip) {
+            return Err(Error::InvalidAddress(
+                "The ZIP code has to be five digits, optionally followed by a dash and four more",
+            ));
+        }
+
+        Ok(self)
+    }
+}
+
+fn is_zip_code(zip: &str) -> bool {
+    let digits = |part: &str, len| part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+
+    match zip.split_once('-') {
+        Some((zip, plus_four)) => digits(zip, 5) && digits(plus_four, 4),
+        None => digits(zip, 5),
+    }
+}
+
+/// On one line, which is how geocoders want it
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, ", self.line1)?;
+
+        if !self.line2.is_empty() {
+            write!(f, "{}, ", self.line2)?;
+        }
+
+        write!(f, "{}, {} {}", self.city, self.state, self.zip)
+    }
+}
+
+impl ArchivedAddress {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "line1": &*self.line1,
+            "line2": &*self.line2,
+            "city": &*self.city,
+            "state": &*self.state,
+            "zip": &*self.zip,
+        })
+    }
+
+    /// Roughly where the address is, without the street address
+    pub fn area_to_json(&self) -> serde_json::Value {
+        json!({
+            "city": &*self.city,
+            "state": &*self.state,
+            "zip": &*self.zip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(line1: &str, line2: &str, city: &str, state: &str, zip: &str) -> Address {
+        Address::new(
+            line1.to_owned(),
+            line2.to_owned(),
+            city.to_owned(),
+            state.to_owned(),
+            zip.to_owned(),
+        )
+    }
+
+    #[test]
+    fn validating() {
+        let valid = address(" 123 Main St ", "", " Minneapolis", "mn", "55401 ")
+            .validate()
+            .unwrap();
+        assert_eq!(valid.to_string(), "123 Main St, Minneapolis, MN 55401");
+
+        assert!(
+            address("123 Main St", "", "Minneapolis", "PR", "00901-1234")
+                .validate()
+                .is_ok()
+        );
+
+        for invalid in [
+            address(" ", "", "Minneapolis", "MN", "55401"),
+            address("123 Main St", "", "", "MN", "55401"),
+            address("123 Main St", "", "Minneapolis", "Minnesota", "55401"),
+            address("123 Main St", "", "Minneapolis", "XX", "55401"),
+            address("123 Main St", "", "Minneapolis", "MN", "5540"),
+            address("123 Main St", "", "Minneapolis", "MN", "55401-12"),
+            address("123 Main St", "", "Minneapolis", "MN", "5540a"),
+        ] {
+            assert!(matches!(invalid.validate(), Err(Error::InvalidAddress(_))));
+        }
+    }
+
+    #[test]
+    fn second_lines_are_only_written_when_there_is_one() {
+        assert_eq!(
+            address("511 Kenwood Pkwy", "Apt 2", "Minneapolis", "MN", "55403").to_string(),
+            "511 Kenwood Pkwy, Apt 2, Minneapolis, MN 55403"
+        );
+    }
+
+    #[test]
+    fn zip_codes() {
+        assert!(is_zip_code("55401"));
+        assert!(is_zip_code("55401-1234"));
+        assert!(!is_zip_code(""));
+        assert!(!is_zip_code("554011234"));
+        assert!(!is_zip_code("55401-"));
+        assert!(!is_zip_code("-1234"));
+    }
+}
@@ -0,0 +1,194 @@
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+
+const HOUR: i64 = 60 * 60 * 1000;
+const WEEK: i64 = 7 * 24 * HOUR;
+
+/// The first Monday after the UNIX epoch, in milliseconds
+const FIRST_MONDAY: i64 = 4 * 24 * HOUR;
+
+/// How a volunteer gets to requests
+#[derive(
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    Archive,
+    RkyvSerialize,
+    RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum TravelMode {
+    Walking,
+    Cycling,
+    #[default]
+    Driving,
+}
+
+impl TravelMode {
+    /// A rough average speed in kilometers per hour, erring on the slow side
+    fn speed(self) -> f64 {
+        match self {
+            TravelMode::Walking => 4.,
+            TravelMode::Cycling => 12.,
+            TravelMode::Driving => 30.,
+        }
+    }
+
+    /// About how many milliseconds it takes to travel `dist` meters
+    pub fn travel_time(self, dist: f64) -> i64 {
+        (dist / 1000. / self.speed() * HOUR as f64) as i64
+    }
+}
+
+/// A span of time that repeats every week, in minutes since Monday midnight UTC
+#[derive(Clone, Copy, Archive, RkyvSerialize, RkyvDeserialize, Serialize, Deserialize, Debug)]
+#[archive(as = "Self")]
+pub struct WeeklyWindow {
+    start: u32,
+    end: u32,
+}
+
+impl WeeklyWindow {
+    pub fn validate(self) -> Result<(), Error> {
+        if self.end <= self.start {
+            return Err(Error::InvalidProfile(
+                "An availability window has to end after it starts",
+            ));
+        }
+
+        if i64::from(self.end) * 60 * 1000 > WEEK {
+            return Err(Error::InvalidProfile(
+                "An availability window has to end within the week",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether any of `availability` overlaps the time from `from` to `until`, in milliseconds since the UNIX epoch. Having no windows means always being available.
+pub fn is_available(availability: &[WeeklyWindow], from: i64, until: i64) -> bool {
+    if from >= until {
+        return false;
+    }
+
+    if availability.is_empty() {
+        return true;
+    }
+
+    let mut week = from - (from - FIRST_MONDAY).rem_euclid(WEEK);
+
+    while week < until {
+        let overlaps = availability.iter().any(|window| {
+            let start = week + i64::from(window.start) * 60 * 1000;
+            let end = week + i64::from(window.end) * 60 * 1000;
+
+            start < until && end > from
+        });
+
+        if overlaps {
+            return true;
+        }
+
+        week += WEEK;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60 * 1000;
+
+    /// From `start` to `end` minutes since Monday midnight
+    fn window(start: u32, end: u32) -> WeeklyWindow {
+        WeeklyWindow { start, end }
+    }
+
+    #[test]
+    fn validating_windows() {
+        assert!(window(0, 60).validate().is_ok());
+        assert!(window(0, 7 * 24 * 60).validate().is_ok());
+        assert!(window(60, 60).validate().is_err());
+        assert!(window(60, 0).validate().is_err());
+        assert!(window(0, 7 * 24 * 60 + 1).validate().is_err());
+    }
+
+    #[test]
+    fn overlapping_windows() {
+        // Mondays from 9 to 11
+        let availability = [window(9 * 60, 11 * 60)];
+        let monday = FIRST_MONDAY + 10 * WEEK;
+
+        assert!(is_available(
+            &availability,
+            monday + 10 * HOUR,
+            monday + 12 * HOUR
+        ));
+        assert!(is_available(
+            &availability,
+            monday + 8 * HOUR,
+            monday + 9 * HOUR + MINUTE
+        ));
+
+        // Touching the window isn't overlapping it
+        assert!(!is_available(
+            &availability,
+            monday + 11 * HOUR,
+            monday + 12 * HOUR
+        ));
+        assert!(!is_available(
+            &availability,
+            monday + 24 * HOUR,
+            monday + 48 * HOUR
+        ));
+
+        // Spanning into the next week
+        assert!(is_available(
+            &availability,
+            monday + 2 * 24 * HOUR,
+            monday + WEEK + 10 * HOUR
+        ));
+    }
+
+    #[test]
+    fn windows_across_the_weekend() {
+        // Sunday evening
+        let availability = [window(6 * 24 * 60 + 18 * 60, 7 * 24 * 60)];
+        let sunday = FIRST_MONDAY + 6 * 24 * HOUR;
+
+        assert!(is_available(
+            &availability,
+            sunday + 20 * HOUR,
+            sunday + 30 * HOUR
+        ));
+        assert!(!is_available(
+            &availability,
+            sunday + 24 * HOUR,
+            sunday + 30 * HOUR
+        ));
+    }
+
+    #[test]
+    fn no_windows_or_no_time() {
+        assert!(is_available(&[], 0, 1));
+        assert!(!is_available(&[], 1, 1));
+        assert!(!is_available(&[window(0, 7 * 24 * 60)], 2, 1));
+    }
+
+    #[test]
+    fn travel_times() {
+        assert_eq!(TravelMode::Walking.travel_time(4000.), HOUR);
+        assert_eq!(TravelMode::Driving.travel_time(15_000.), 30 * MINUTE);
+        assert!(TravelMode::Cycling.travel_time(1000.) < TravelMode::Walking.travel_time(1000.));
+    }
+}
@@ -1,27 +1,124 @@
+mod sqlite;
+
+use std::cell::Cell;
+use std::env;
+use std::ops::Bound::{Excluded, Unbounded};
 use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::thread;
+use std::time::Duration;
 use std::{any::type_name, marker::PhantomData, sync::Arc};
 
 use crate::errors::Error;
 
-use log::{info, trace};
+use log::{debug, info, trace, warn};
+use once_cell::sync::Lazy;
 use ouroboros::self_referencing;
 use rkyv::ser::serializers::AllocSerializer;
 use rkyv::{Archive, Deserialize, Fallible};
+use rusqlite::Connection;
 use sha3::Digest;
 use sled::transaction::{
-    ConflictableTransactionError, ConflictableTransactionResult, TransactionResult,
-    TransactionalTree,
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionResult, TransactionalTree,
 };
 use sled::IVec;
 use sled::Transactional as SledTransactional;
 
+use self::sqlite::{SqliteStorage, SqliteTree};
+
+/// Where every `Db` is stored, selected with the `DB_BACKEND` (`sled` or `sqlite`) and `DB_PATH` environment variables.
+#[derive(Clone)]
+pub enum Storage {
+    Sled(sled::Db),
+    Sqlite(SqliteStorage),
+}
+
+impl Storage {
+    pub fn from_env() -> Storage {
+        let backend = env::var("DB_BACKEND").unwrap_or_else(|_| "sled".to_owned());
+
+        match backend.as_str() {
+            "sled" => {
+                let path = env::var("DB_PATH").unwrap_or_else(|_| "db".to_owned());
+                info!("Using the sled backend at {path}");
+
+                Storage::Sled(sled::open(path).expect("the DB to open properly"))
+            }
+            "sqlite" => {
+                let path = env::var("DB_PATH").unwrap_or_else(|_| "db.sqlite3".to_owned());
+                info!("Using the SQLite backend at {path}");
+
+                Storage::Sqlite(SqliteStorage::open(&path).expect("the DB to open properly"))
+            }
+            other => panic!("Unknown DB_BACKEND `{other}`, expected `sled` or `sqlite`"),
+        }
+    }
+}
+
+/// Copies the sled database at `sled_path` into a SQLite database at `sqlite_path`.
+pub fn migrate_sled_to_sqlite(sled_path: &str, sqlite_path: &str) -> Result<(), Error> {
+    info!("Migrating the sled database at {sled_path} to SQLite at {sqlite_path}");
+
+    let sled = sled::open(sled_path).map_err(Error::unexpected)?;
+    let sqlite = SqliteStorage::open(sqlite_path)?;
+
+    sqlite::migrate_from_sled(&sled, &sqlite)
+}
+
+enum Tree {
+    Sled(sled::Tree),
+    Sqlite(SqliteTree),
+}
+
+enum TreeView<'a> {
+    Sled(&'a TransactionalTree),
+    Sqlite(&'a Connection, &'a SqliteTree),
+}
+
+/// Which part of a tree to walk. Keys are compared as raw bytes, which is the same order for both backends.
+#[derive(Clone, Copy)]
+enum Scan<'a> {
+    All,
+    After(&'a [u8]),
+    Prefix(&'a [u8]),
+}
+
+enum RawIter {
+    Sled {
+        iter: Box<sled::Iter>,
+        reverse: bool,
+    },
+    Sqlite(std::vec::IntoIter<(IVec, IVec)>),
+}
+
+impl Iterator for RawIter {
+    type Item = Result<(IVec, IVec), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            RawIter::Sled { iter, reverse } => {
+                let next = if *reverse {
+                    iter.next_back()
+                } else {
+                    iter.next()
+                };
+
+                next.map(|v| v.map_err(Error::unexpected))
+            }
+            RawIter::Sqlite(iter) => iter.next().map(Ok),
+        }
+    }
+}
+
 pub struct Db<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive>(
-    Arc<sled::Tree>,
+    Arc<Tree>,
     PhantomData<T>,
 );
 
 pub struct Transaction<'a, const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive>(
-    &'a TransactionalTree,
+    TreeView<'a>,
     PhantomData<T>,
 );
 
@@ -99,20 +196,49 @@ where
     }
 }
 
-impl<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive> Db<N, T> {
-    pub fn open(db: &sled::Db, string: &str) -> Db<N, T> {
+impl<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive> Db<N, T>
+where
+    T::Archived: 'static,
+{
+    /// The name the database was opened with.
+    pub fn name(&self) -> Result<String, Error> {
+        match &*self.0 {
+            Tree::Sled(tree) => String::from_utf8(tree.name().to_vec()).map_err(Error::unexpected),
+            Tree::Sqlite(tree) => Ok(tree.name().to_owned()),
+        }
+    }
+
+    /// Views the same tree as holding `U`s instead. This is only meant for reading values stored with an older layout while migrating them.
+    pub fn retype<const M: usize, U: rkyv::Serialize<AllocSerializer<M>> + Archive>(
+        &self,
+    ) -> Db<M, U> {
+        Db(Arc::clone(&self.0), PhantomData)
+    }
+
+    pub fn open(storage: &Storage, string: &str) -> Db<N, T> {
         info!("Opening {} DB from {string}", type_name::<T>());
 
-        Db(
-            Arc::new(db.open_tree(string).expect("the database to be available")),
-            PhantomData,
-        )
+        let tree = match storage {
+            Storage::Sled(db) => {
+                Tree::Sled(db.open_tree(string).expect("the database to be available"))
+            }
+            Storage::Sqlite(db) => {
+                Tree::Sqlite(db.open_table(string).expect("the database to be available"))
+            }
+        };
+
+        Db(Arc::new(tree), PhantomData)
     }
 
     pub fn get(&self, key: &str) -> Result<Option<Archived<T>>, Error> {
         trace!("Getting `{key}` from the {} database", type_name::<T>());
 
-        match self.0.get(key).map_err(Error::unexpected)? {
+        let maybe_v = match &*self.0 {
+            Tree::Sled(tree) => tree.get(key).map_err(Error::unexpected)?,
+            Tree::Sqlite(tree) => tree.get(key.as_bytes())?,
+        };
+
+        match maybe_v {
             Some(v) => Ok(Some(Archived::deserialize(v)?)),
             None => Ok(None),
         }
@@ -121,66 +247,297 @@ impl<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive> Db<N, T>
     pub fn iter(&self) -> impl Iterator<Item = Result<(String, Archived<T>), Error>> {
         trace!("Iterating the {} database", type_name::<T>());
 
-        self.0.iter().map(|maybe_v| {
-            let (key, val) = maybe_v.map_err(Error::unexpected)?;
+        self.scan(Scan::All, false, None)
+    }
+
+    /// Iterates the database from the largest key to the smallest.
+    #[allow(dead_code)]
+    pub fn iter_rev(&self) -> impl Iterator<Item = Result<(String, Archived<T>), Error>> {
+        trace!("Iterating the {} database in reverse", type_name::<T>());
 
-            let str = String::from_utf8(key.to_vec()).map_err(Error::unexpected)?;
-            let t = Archived::deserialize(val)?;
+        self.scan(Scan::All, true, None)
+    }
 
-            Ok((str, t))
-        })
+    /// Iterates every key starting with `prefix`.
+    pub fn scan_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl Iterator<Item = Result<(String, Archived<T>), Error>> {
+        trace!(
+            "Scanning the {} database for the prefix `{prefix}`",
+            type_name::<T>()
+        );
+
+        self.scan(Scan::Prefix(prefix.as_bytes()), false, None)
+    }
+
+    /// Returns up to `limit` entries whose keys come strictly after `cursor`. Passing the last key of one page as the cursor gives the next page, and an empty cursor starts from the beginning.
+    pub fn scan_from(
+        &self,
+        cursor: &str,
+        limit: usize,
+    ) -> impl Iterator<Item = Result<(String, Archived<T>), Error>> {
+        trace!(
+            "Scanning {limit} entries after `{cursor}` in the {} database",
+            type_name::<T>()
+        );
+
+        self.scan(Scan::After(cursor.as_bytes()), false, Some(limit))
+    }
+
+    fn scan(
+        &self,
+        scan: Scan,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> impl Iterator<Item = Result<(String, Archived<T>), Error>> {
+        let raw = match &*self.0 {
+            Tree::Sled(tree) => {
+                let iter = match scan {
+                    Scan::All => tree.iter(),
+                    Scan::After(cursor) => tree.range::<&[u8], _>((Excluded(cursor), Unbounded)),
+                    Scan::Prefix(prefix) => tree.scan_prefix(prefix),
+                };
+
+                Ok(RawIter::Sled {
+                    iter: Box::new(iter),
+                    reverse,
+                })
+            }
+            // SQLite rows can't outlive the connection lock, so they're read up front
+            Tree::Sqlite(tree) => tree
+                .entries(scan, reverse, limit)
+                .map(|v| RawIter::Sqlite(v.into_iter())),
+        };
+
+        let (raw, error) = match raw {
+            Ok(raw) => (Some(raw), None),
+            Err(e) => (None, Some(Err(e))),
+        };
+
+        error.into_iter().chain(
+            raw.into_iter()
+                .flatten()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|maybe_v| {
+                    let (key, val) = maybe_v?;
+
+                    let str = String::from_utf8(key.to_vec()).map_err(Error::unexpected)?;
+                    let t = Archived::deserialize(val)?;
+
+                    Ok((str, t))
+                }),
+        )
+    }
+}
+
+/// How transactions back off when they keep conflicting, configured with the `DB_TRANSACTION_MAX_RETRIES`, `DB_TRANSACTION_BACKOFF_MS` and `DB_TRANSACTION_MAX_BACKOFF_MS` environment variables.
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> RetryPolicy {
+        fn var<T: FromStr>(name: &str, default: T) -> T {
+            match env::var(name) {
+                Ok(v) => v
+                    .parse()
+                    .unwrap_or_else(|_| panic!("`{name}` should be a non-negative integer")),
+                Err(_) => default,
+            }
+        }
+
+        RetryPolicy {
+            max_retries: var("DB_TRANSACTION_MAX_RETRIES", 10),
+            base_backoff: Duration::from_millis(var("DB_TRANSACTION_BACKOFF_MS", 1)),
+            max_backoff: Duration::from_millis(var("DB_TRANSACTION_MAX_BACKOFF_MS", 100)),
+        }
+    }
+
+    /// Exponential backoff with full jitter, so contending transactions spread out instead of colliding again.
+    fn backoff(&self, conflicts: u32) -> Duration {
+        let ceiling = self
+            .base_backoff
+            .saturating_mul(1 << conflicts.min(16))
+            .min(self.max_backoff);
+
+        ceiling.mul_f64(rand::random::<f64>())
     }
 }
 
+static RETRY_POLICY: Lazy<RetryPolicy> = Lazy::new(RetryPolicy::from_env);
+
+/// Running totals across every transaction, logged whenever a transaction conflicts.
+struct TransactionMetrics {
+    committed: AtomicU64,
+    conflicts: AtomicU64,
+    gave_up: AtomicU64,
+}
+
+static TRANSACTION_METRICS: TransactionMetrics = TransactionMetrics {
+    committed: AtomicU64::new(0),
+    conflicts: AtomicU64::new(0),
+    gave_up: AtomicU64::new(0),
+};
+
 pub trait Transactional {
     type View<'a>;
 
-    fn transaction<A, F>(&self, f: F) -> TransactionResult<A, Error>
+    /// Runs a single attempt of the transaction. The backend may call `f` again if it detects a conflict.
+    fn transaction_attempt<A, F>(&self, f: F) -> TransactionResult<A, Error>
     where
         F: for<'a> Fn(Self::View<'a>) -> ConflictableTransactionResult<A, Error>;
-}
-
-impl<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive> Transactional for Db<N, T> {
-    type View<'a> = Transaction<'a, N, T>;
 
+    /// Runs `f` in a transaction, backing off and retrying according to the `RetryPolicy` when it conflicts with another transaction.
     fn transaction<A, F>(&self, f: F) -> TransactionResult<A, Error>
     where
         F: for<'a> Fn(Self::View<'a>) -> ConflictableTransactionResult<A, Error>,
     {
-        self.0.transaction(|t| {
-            let transaction = Transaction(t, PhantomData);
+        let mut conflicts = 0;
 
-            f(transaction)
-        })
+        loop {
+            let called = Cell::new(false);
+
+            let result = self.transaction_attempt(|view| {
+                // The backend is retrying, abort instead so that the backoff happens without holding its locks
+                if called.replace(true) {
+                    return Err(Error::TransactionConflict.into());
+                }
+
+                f(view)
+            });
+
+            match result {
+                Err(TransactionError::Abort(Error::TransactionConflict)) => {
+                    conflicts += 1;
+
+                    let total_conflicts = TRANSACTION_METRICS.conflicts.fetch_add(1, Relaxed) + 1;
+
+                    if conflicts > RETRY_POLICY.max_retries {
+                        let gave_up = TRANSACTION_METRICS.gave_up.fetch_add(1, Relaxed) + 1;
+
+                        warn!(
+                            "A transaction on {} gave up after {conflicts} conflicts ({gave_up} transactions have given up, {total_conflicts} conflicts and {} commits in total)",
+                            type_name::<Self>(),
+                            TRANSACTION_METRICS.committed.load(Relaxed),
+                        );
+
+                        return Err(TransactionError::Abort(Error::TransactionConflict));
+                    }
+
+                    let backoff = RETRY_POLICY.backoff(conflicts);
+
+                    debug!(
+                        "A transaction on {} conflicted, retrying in {backoff:?} ({total_conflicts} conflicts in total)",
+                        type_name::<Self>(),
+                    );
+
+                    thread::sleep(backoff);
+                }
+                result => {
+                    if result.is_ok() {
+                        TRANSACTION_METRICS.committed.fetch_add(1, Relaxed);
+                    }
+
+                    return result;
+                }
+            }
+        }
     }
 }
 
-impl<
-        const N1: usize,
-        T1: rkyv::Serialize<AllocSerializer<N1>> + Archive,
-        const N2: usize,
-        T2: rkyv::Serialize<AllocSerializer<N2>> + Archive,
-    > Transactional for (&Db<N1, T1>, &Db<N2, T2>)
-{
-    type View<'a> = (Transaction<'a, N1, T1>, Transaction<'a, N2, T2>);
+impl<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive> Transactional for Db<N, T> {
+    type View<'a> = Transaction<'a, N, T>;
 
-    fn transaction<A, F>(&self, f: F) -> TransactionResult<A, Error>
+    fn transaction_attempt<A, F>(&self, f: F) -> TransactionResult<A, Error>
     where
         F: for<'a> Fn(Self::View<'a>) -> ConflictableTransactionResult<A, Error>,
     {
-        (&*self.0 .0, &*self.1 .0).transaction(|(t1, t2)| {
-            let transaction = (Transaction(t1, PhantomData), Transaction(t2, PhantomData));
+        match &*self.0 {
+            Tree::Sled(tree) => tree.transaction(|t| {
+                let transaction = Transaction(TreeView::Sled(t), PhantomData);
 
-            f(transaction)
-        })
+                f(transaction)
+            }),
+            Tree::Sqlite(tree) => tree.storage().transaction(|c| {
+                let transaction = Transaction(TreeView::Sqlite(c, tree), PhantomData);
+
+                f(transaction)
+            }),
+        }
     }
 }
 
+/// Implements `Transactional` for a tuple of `&Db`s. Every `Db` has to live in the same storage.
+macro_rules! impl_transactional_tuple {
+    ($(($n:ident, $t:ident, $idx:tt, $tree:ident)),+) => {
+        impl<$(const $n: usize, $t: rkyv::Serialize<AllocSerializer<$n>> + Archive),+> Transactional
+            for ($(&Db<$n, $t>,)+)
+        {
+            type View<'a> = ($(Transaction<'a, $n, $t>,)+);
+
+            fn transaction_attempt<A, F>(&self, f: F) -> TransactionResult<A, Error>
+            where
+                F: for<'a> Fn(Self::View<'a>) -> ConflictableTransactionResult<A, Error>,
+            {
+                match ($(&*self.$idx.0,)+) {
+                    ($(Tree::Sled($tree),)+) => ($($tree,)+).transaction(|($($tree,)+)| {
+                        let transaction = ($(Transaction(TreeView::Sled($tree), PhantomData),)+);
+
+                        f(transaction)
+                    }),
+                    ($(Tree::Sqlite($tree),)+) => {
+                        let storages = [$($tree.storage()),+];
+
+                        if !storages.iter().all(|storage| storage.is_same(storages[0])) {
+                            return Err(TransactionError::Abort(Error::msg(
+                                "Transactions can't span databases in different storage",
+                            )));
+                        }
+
+                        storages[0].transaction(|c| {
+                            let transaction = ($(Transaction(TreeView::Sqlite(c, $tree), PhantomData),)+);
+
+                            f(transaction)
+                        })
+                    }
+                    _ => Err(TransactionError::Abort(Error::msg(
+                        "Transactions can't span databases in different storage",
+                    ))),
+                }
+            }
+        }
+    };
+}
+
+impl_transactional_tuple!((N1, T1, 0, tree1), (N2, T2, 1, tree2));
+impl_transactional_tuple!((N1, T1, 0, tree1), (N2, T2, 1, tree2), (N3, T3, 2, tree3));
+impl_transactional_tuple!(
+    (N1, T1, 0, tree1),
+    (N2, T2, 1, tree2),
+    (N3, T3, 2, tree3),
+    (N4, T4, 3, tree4)
+);
+impl_transactional_tuple!(
+    (N1, T1, 0, tree1),
+    (N2, T2, 1, tree2),
+    (N3, T3, 2, tree3),
+    (N4, T4, 3, tree4),
+    (N5, T5, 4, tree5)
+);
+
 impl<'a, const N: usize, T: rkyv::Serialize<AllocSerializer<N>>> Transaction<'a, N, T> {
     pub fn add(&self, key: &str, val: &T) -> Result<(), ConflictableTransactionError<Error>> {
         trace!("Adding `{key}` to the {} database", type_name::<T>());
 
-        self.0.insert(key, serialize(val)?)?;
+        match &self.0 {
+            TreeView::Sled(t) => {
+                t.insert(key, serialize(val)?)?;
+            }
+            TreeView::Sqlite(c, tree) => tree.insert_with(c, key.as_bytes(), &serialize(val)?)?,
+        }
 
         Ok(())
     }
@@ -191,7 +548,12 @@ impl<'a, const N: usize, T: rkyv::Serialize<AllocSerializer<N>>> Transaction<'a,
     ) -> Result<Option<Archived<T>>, ConflictableTransactionError<Error>> {
         trace!("Getting `{key}` from the {} database", type_name::<T>());
 
-        match self.0.get(key).map_err(Error::unexpected)? {
+        let maybe_v = match &self.0 {
+            TreeView::Sled(t) => t.get(key).map_err(Error::unexpected)?,
+            TreeView::Sqlite(c, tree) => tree.get_with(c, key.as_bytes())?,
+        };
+
+        match maybe_v {
             Some(v) => Ok(Some(Archived::deserialize(v)?)),
             None => Ok(None),
         }
@@ -203,13 +565,80 @@ impl<'a, const N: usize, T: rkyv::Serialize<AllocSerializer<N>>> Transaction<'a,
     ) -> Result<Option<Archived<T>>, ConflictableTransactionError<Error>> {
         trace!("Deleting {key} from the {} database", type_name::<T>());
 
-        match self.0.remove(key).map_err(Error::unexpected)? {
+        let maybe_v = match &self.0 {
+            TreeView::Sled(t) => t.remove(key).map_err(Error::unexpected)?,
+            TreeView::Sqlite(c, tree) => tree.remove_with(c, key.as_bytes())?,
+        };
+
+        match maybe_v {
             Some(v) => Ok(Some(Archived::deserialize(v)?)),
             None => Ok(None),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database with the same keys in each backend
+    fn databases() -> Vec<Db<64, String>> {
+        let storages = [
+            Storage::Sled(sled::Config::new().temporary(true).open().unwrap()),
+            Storage::Sqlite(SqliteStorage::open(":memory:").unwrap()),
+        ];
+
+        storages
+            .iter()
+            .map(|storage| {
+                let db = Db::open(storage, "scans");
+
+                db.transaction(|db| {
+                    for key in ["a/1", "a/2", "a/3", "ab/1", "b/1"] {
+                        db.add(key, &key.to_uppercase())?;
+                    }
+
+                    Ok(())
+                })
+                .unwrap();
+
+                db
+            })
+            .collect()
+    }
+
+    fn keys(iter: impl Iterator<Item = Result<(String, Archived<String>), Error>>) -> Vec<String> {
+        iter.map(|maybe_entry| {
+            let (key, value) = maybe_entry.unwrap();
+            assert_eq!(key.to_uppercase(), value.as_str());
+
+            key
+        })
+        .collect()
+    }
+
+    #[test]
+    fn scanning_prefixes() {
+        for db in databases() {
+            assert_eq!(keys(db.scan_prefix("a/")), ["a/1", "a/2", "a/3"]);
+            assert_eq!(keys(db.scan_prefix("a")), ["a/1", "a/2", "a/3", "ab/1"]);
+            assert_eq!(keys(db.scan_prefix("c")), Vec::<String>::new());
+        }
+    }
 
-    pub fn generate_id(&self) -> Result<u64, ConflictableTransactionError<Error>> {
-        self.0.generate_id().map_err(|e| e.into())
+    #[test]
+    fn iterating_in_reverse() {
+        for db in databases() {
+            assert_eq!(keys(db.iter_rev()), ["b/1", "ab/1", "a/3", "a/2", "a/1"]);
+        }
+    }
+
+    #[test]
+    fn scanning_pages() {
+        for db in databases() {
+            assert_eq!(keys(db.scan_from("", 2)), ["a/1", "a/2"]);
+            assert_eq!(keys(db.scan_from("a/2", 2)), ["a/3", "ab/1"]);
+            assert_eq!(keys(db.scan_from("ab/1", 2)), ["b/1"]);
+        }
     }
 }
@@ -0,0 +1,231 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::{info, trace};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionResult,
+};
+use sled::IVec;
+
+use super::Scan;
+use crate::errors::Error;
+
+/// The name sled gives to the tree that `sled::Db` itself derefs to. We never store anything in it.
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// A single SQLite connection shared by every table. Each `Db` gets its own table with the same `(key, value)` layout as a sled tree, so the bytes stored are identical between the two backends.
+#[derive(Clone)]
+pub struct SqliteStorage(Arc<Mutex<Connection>>);
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, Error> {
+        info!("Opening SQLite database at {path}");
+
+        let connection = Connection::open(path).map_err(Error::unexpected)?;
+
+        connection
+            .execute_batch("PRAGMA journal_mode = WAL;")
+            .map_err(Error::unexpected)?;
+
+        Ok(SqliteStorage(Arc::new(Mutex::new(connection))))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>, Error> {
+        self.0
+            .lock()
+            .map_err(|_| Error::msg("The SQLite connection mutex was poisoned"))
+    }
+
+    pub fn is_same(&self, other: &SqliteStorage) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn open_table(&self, name: &str) -> Result<SqliteTree, Error> {
+        let table = quote_identifier(name);
+
+        self.lock()?
+            .execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (key BLOB PRIMARY KEY, value BLOB NOT NULL)"
+            ))
+            .map_err(Error::unexpected)?;
+
+        Ok(SqliteTree {
+            storage: self.to_owned(),
+            name: name.to_owned(),
+            table,
+        })
+    }
+
+    /// Runs `f` inside an immediate transaction, mirroring the semantics of sled's transactions: aborting rolls back and returns the error, conflicting retries.
+    pub fn transaction<A, F>(&self, f: F) -> TransactionResult<A, Error>
+    where
+        F: Fn(&Connection) -> ConflictableTransactionResult<A, Error>,
+    {
+        let mut connection = self.lock().map_err(TransactionError::Abort)?;
+
+        loop {
+            let transaction = connection
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| TransactionError::Abort(Error::unexpected(e)))?;
+
+            // Dropping the transaction without committing rolls it back
+            match f(&transaction) {
+                Ok(v) => {
+                    transaction
+                        .commit()
+                        .map_err(|e| TransactionError::Abort(Error::unexpected(e)))?;
+
+                    return Ok(v);
+                }
+                Err(ConflictableTransactionError::Abort(e)) => {
+                    return Err(TransactionError::Abort(e))
+                }
+                Err(ConflictableTransactionError::Storage(e)) => {
+                    return Err(TransactionError::Storage(e))
+                }
+                Err(ConflictableTransactionError::Conflict) => {
+                    trace!("Retrying a conflicting SQLite transaction");
+                }
+            }
+        }
+    }
+}
+
+pub struct SqliteTree {
+    storage: SqliteStorage,
+    name: String,
+    table: String,
+}
+
+impl SqliteTree {
+    pub fn storage(&self) -> &SqliteStorage {
+        &self.storage
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<IVec>, Error> {
+        self.get_with(&*self.storage.lock()?, key)
+    }
+
+    pub(super) fn entries(
+        &self,
+        scan: Scan,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> Result<Vec<(IVec, IVec)>, Error> {
+        let connection = self.storage.lock()?;
+
+        let (condition, bound) = match scan {
+            Scan::All => ("?1 IS NULL", None),
+            Scan::After(cursor) => ("key > ?1", Some(cursor)),
+            Scan::Prefix(prefix) => ("substr(key, 1, length(?1)) = ?1", Some(prefix)),
+        };
+
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT key, value FROM {} WHERE {condition} ORDER BY key {} LIMIT ?2",
+                self.table,
+                if reverse { "DESC" } else { "ASC" },
+            ))
+            .map_err(Error::unexpected)?;
+
+        // SQLite treats a negative limit as no limit
+        let limit = limit.map_or(-1, |v| v.min(i64::MAX as usize) as i64);
+
+        let rows = statement
+            .query_map(params![bound, limit], |row| {
+                Ok((
+                    IVec::from(row.get::<_, Vec<u8>>(0)?),
+                    IVec::from(row.get::<_, Vec<u8>>(1)?),
+                ))
+            })
+            .map_err(Error::unexpected)?;
+
+        rows.collect::<Result<_, _>>().map_err(Error::unexpected)
+    }
+
+    pub fn get_with(&self, connection: &Connection, key: &[u8]) -> Result<Option<IVec>, Error> {
+        connection
+            .query_row(
+                &format!("SELECT value FROM {} WHERE key = ?1", self.table),
+                params![key],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map(|v| v.map(IVec::from))
+            .map_err(Error::unexpected)
+    }
+
+    pub fn insert_with(
+        &self,
+        connection: &Connection,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        connection
+            .execute(
+                &format!(
+                    "INSERT INTO {} (key, value) VALUES (?1, ?2)
+                    ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                    self.table
+                ),
+                params![key, value],
+            )
+            .map(|_| ())
+            .map_err(Error::unexpected)
+    }
+
+    pub fn remove_with(&self, connection: &Connection, key: &[u8]) -> Result<Option<IVec>, Error> {
+        let old = self.get_with(connection, key)?;
+
+        if old.is_some() {
+            connection
+                .execute(
+                    &format!("DELETE FROM {} WHERE key = ?1", self.table),
+                    params![key],
+                )
+                .map_err(Error::unexpected)?;
+        }
+
+        Ok(old)
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Copies every tree of a sled database into SQLite tables of the same name. The stored bytes are copied verbatim so their hashes stay valid.
+pub fn migrate_from_sled(sled: &sled::Db, sqlite: &SqliteStorage) -> Result<(), Error> {
+    for name in sled.tree_names() {
+        if &*name == SLED_DEFAULT_TREE {
+            continue;
+        }
+
+        let name = String::from_utf8(name.to_vec()).map_err(Error::unexpected)?;
+        let tree = sled.open_tree(&name).map_err(Error::unexpected)?;
+        let table = sqlite.open_table(&name)?;
+
+        let copied = sqlite.transaction(|connection| {
+            let mut copied = 0;
+
+            for entry in tree.iter() {
+                let (key, value) = entry.map_err(Error::unexpected)?;
+
+                table.insert_with(connection, &key, &value)?;
+
+                copied += 1;
+            }
+
+            Ok(copied)
+        })?;
+
+        info!("Migrated {copied} entries from the `{name}` tree");
+    }
+
+    Ok(())
+}
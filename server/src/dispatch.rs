@@ -0,0 +1,350 @@
+//! Optionally offers help requests to volunteers one at a time, so they don't have to keep looking for work with `request-work`. Each offer runs out after a while, and whenever one is declined or runs out the request is offered to the next closest volunteer who can do it, until enough volunteers have accepted.
+
+use std::{collections::HashSet, env, time::Duration};
+
+use chrono::Utc;
+use log::{info, trace, warn};
+use once_cell::sync::Lazy;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+
+use crate::{
+    db::{Db, Transactional},
+    errors::Error,
+    events::EventBus,
+    notifications::{Notification, Notifier},
+    priority::priority,
+    spatial_index::{VolunteerIndex, DISTANCE_TOLERANCE},
+    ArchivedHelpRequest, HelpRequestDB, UserDB, VolunteerProfileDB,
+};
+
+/// Whether requests are offered to volunteers, set with `DISPATCH_ENABLED`
+static ENABLED: Lazy<bool> = Lazy::new(|| match env::var("DISPATCH_ENABLED") {
+    Ok(v) => v
+        .parse()
+        .expect("`DISPATCH_ENABLED` should be `true` or `false`"),
+    Err(_) => false,
+});
+
+/// How long a volunteer has to accept or decline an offer, set with `DISPATCH_OFFER_MINUTES`
+static OFFER_DURATION: Lazy<i64> = Lazy::new(|| {
+    let minutes: i64 = match env::var("DISPATCH_OFFER_MINUTES") {
+        Ok(v) => v
+            .parse()
+            .expect("`DISPATCH_OFFER_MINUTES` should be a non-negative integer"),
+        Err(_) => 15,
+    };
+
+    minutes * 60 * 1000
+});
+
+/// How often offers are checked for having run out
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Who a help request has been offered to
+#[derive(Clone, Default, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct Dispatch {
+    /// The volunteer the request is offered to right now
+    offered_to: Option<String>,
+    /// When the current offer runs out
+    expires: i64,
+    /// Volunteers who declined, let their offer run out or withdrew, who won't be offered it again
+    passed: Vec<String>,
+}
+
+/// Keyed by help request ID
+pub type DispatchDB = Db<100, Dispatch>;
+
+impl ArchivedDispatch {
+    pub fn is_offered_to(&self, username: &str, now: i64) -> bool {
+        matches!(self.offered_to.as_ref(), Some(volunteer) if volunteer == username)
+            && self.expires > now
+    }
+
+    pub fn expires(&self) -> i64 {
+        self.expires
+    }
+}
+
+#[derive(Clone)]
+pub struct Dispatcher {
+    users: UserDB,
+    help_requests: HelpRequestDB,
+    volunteer_profiles: VolunteerProfileDB,
+    dispatches: DispatchDB,
+    volunteers: VolunteerIndex,
+    events: EventBus,
+    notifier: Notifier,
+}
+
+impl Dispatcher {
+    pub fn new(
+        users: &UserDB,
+        help_requests: &HelpRequestDB,
+        volunteer_profiles: &VolunteerProfileDB,
+        dispatches: &DispatchDB,
+        volunteers: &VolunteerIndex,
+        events: &EventBus,
+        notifier: &Notifier,
+    ) -> Dispatcher {
+        Dispatcher {
+            users: users.to_owned(),
+            help_requests: help_requests.to_owned(),
+            volunteer_profiles: volunteer_profiles.to_owned(),
+            dispatches: dispatches.to_owned(),
+            volunteers: volunteers.to_owned(),
+            events: events.to_owned(),
+            notifier: notifier.to_owned(),
+        }
+    }
+
+    pub fn dispatches(&self) -> &DispatchDB {
+        &self.dispatches
+    }
+
+    /// Offers the request to the next volunteer if it still needs volunteers and its current offer has been answered or run out, and tells them about it. Returns whether a new offer was made.
+    pub fn dispatch(&self, id: &str) -> Result<bool, Error> {
+        if !*ENABLED {
+            return Ok(false);
+        }
+
+        let now = Utc::now().timestamp_millis();
+
+        let request = match self.help_requests.get(id)? {
+            Some(v) if !v.state.is_finished() => v,
+            _ => {
+                self.dispatches.transaction(|dispatches_db| {
+                    dispatches_db.delete(id)?;
+                    Ok(())
+                })?;
+
+                return Ok(false);
+            }
+        };
+
+        let current = self.dispatches.get(id)?;
+        let read = current.as_ref().map(|dispatch| {
+            (
+                dispatch.offered_to.as_ref().map(|v| v.to_string()),
+                dispatch.expires,
+            )
+        });
+        let mut dispatch = current
+            .map(|dispatch| dispatch.to_original())
+            .unwrap_or_default();
+
+        if let Some(volunteer) = dispatch.offered_to.take() {
+            if request.state.is_accepted_by(&volunteer) {
+                trace!("{volunteer} accepted the request they were offered");
+            } else if dispatch.expires > now {
+                return Ok(false);
+            } else {
+                trace!("{volunteer}'s offer ran out");
+                dispatch.passed.push(volunteer);
+            }
+        }
+
+        if request.state.is_open(request.volunteers_needed) {
+            dispatch.offered_to = self.best_volunteer(id, &request, &dispatch.passed, now)?;
+            dispatch.expires = now + *OFFER_DURATION;
+        }
+
+        let written = self.dispatches.transaction(|dispatches_db| {
+            // Someone else may have answered or remade the offer since it was read
+            let latest = dispatches_db.get(id)?.map(|dispatch| {
+                (
+                    dispatch.offered_to.as_ref().map(|v| v.to_string()),
+                    dispatch.expires,
+                )
+            });
+
+            if latest != read {
+                return Ok(false);
+            }
+
+            dispatches_db.add(id, &dispatch)?;
+
+            Ok(true)
+        })?;
+
+        let offered_to = match dispatch.offered_to {
+            Some(volunteer) if written => volunteer,
+            _ => return Ok(false),
+        };
+
+        self.events
+            .request_offered(id, &offered_to, dispatch.expires);
+
+        if let Err(e) = self.notifier.notify(&offered_to, Notification::offered(id)) {
+            warn!("Failed to tell a volunteer a request was offered to them: {e:?}");
+        }
+
+        Ok(true)
+    }
+
+    /// Stops offering the request to `username` and offers it to the next volunteer. Fails if it isn't offered to them right now.
+    pub fn decline(&self, id: &str, username: &str) -> Result<(), Error> {
+        let now = Utc::now().timestamp_millis();
+
+        self.dispatches.transaction(|dispatches_db| {
+            let mut dispatch = match dispatches_db.get(id)? {
+                Some(v) if v.is_offered_to(username, now) => v.to_original(),
+                _ => return Err(Error::NotOffered.into()),
+            };
+
+            dispatch.offered_to = None;
+            dispatch.passed.push(username.to_owned());
+
+            dispatches_db.add(id, &dispatch)?;
+
+            Ok(())
+        })?;
+
+        self.dispatch(id)?;
+
+        Ok(())
+    }
+
+    /// Makes sure a volunteer who withdrew from a request isn't offered it again, and offers it to someone else.
+    pub fn withdrawn(&self, id: &str, username: &str) -> Result<(), Error> {
+        if !*ENABLED {
+            return Ok(());
+        }
+
+        self.dispatches.transaction(|dispatches_db| {
+            let mut dispatch = dispatches_db
+                .get(id)?
+                .map(|v| v.to_original())
+                .unwrap_or_default();
+
+            dispatch.passed.push(username.to_owned());
+
+            dispatches_db.add(id, &dispatch)?;
+
+            Ok(())
+        })?;
+
+        self.dispatch(id)?;
+
+        Ok(())
+    }
+
+    /// The volunteer the request would rank highest for in their work list, who can do it and isn't already considering another offer. The request's priority only falls with distance, so volunteers are looked at from nearest to farthest until nobody left could rank it higher.
+    fn best_volunteer(
+        &self,
+        id: &str,
+        request: &ArchivedHelpRequest,
+        passed: &[String],
+        now: i64,
+    ) -> Result<Option<String>, Error> {
+        let mut busy = HashSet::new();
+
+        for maybe_dispatch in self.dispatches.iter() {
+            let (other_id, dispatch) = maybe_dispatch?;
+
+            if other_id != id && dispatch.expires > now {
+                if let Some(volunteer) = dispatch.offered_to.as_ref() {
+                    busy.insert(volunteer.to_string());
+                }
+            }
+        }
+
+        let rank = |dist: f64| {
+            priority(
+                dist,
+                now - request.creation_time,
+                &request.urgency,
+                request.unhelped_before,
+            )
+        };
+
+        let mut best: Option<(f64, String)> = None;
+
+        self.volunteers.with_nearest(request.location, |nearest| {
+            for (username, approximate_dist) in nearest {
+                // Everyone left is too far away to rank the request higher than the best volunteer found
+                if matches!(&best, Some((best_priority, _)) if rank(approximate_dist / DISTANCE_TOLERANCE) < *best_priority)
+                {
+                    break;
+                }
+
+                if passed.iter().any(|volunteer| volunteer == username)
+                    || busy.contains(username)
+                    || request.state.is_accepted_by(username)
+                {
+                    continue;
+                }
+
+                let user = match self.users.get(username)? {
+                    Some(v) => v,
+                    None => continue,
+                };
+
+                let dist = request.distance_meters(user.location);
+
+                if dist.is_nan() {
+                    continue;
+                }
+
+                let candidate = rank(dist);
+
+                if matches!(&best, Some((best_priority, _)) if candidate <= *best_priority) {
+                    continue;
+                }
+
+                if let Some(profile) = self.volunteer_profiles.get(username)? {
+                    if !profile.can_do(request)
+                        || !profile.can_reach(dist, request.work_span(), now)
+                    {
+                        continue;
+                    }
+                }
+
+                best = Some((candidate, username.to_owned()));
+            }
+
+            Ok::<_, Error>(())
+        })?;
+
+        Ok(best.map(|(_, username)| username))
+    }
+
+    /// Offers every request whose offer has run out to the next volunteer, and forgets finished requests.
+    fn dispatch_all(&self) -> Result<usize, Error> {
+        let mut offered = 0;
+
+        for maybe_dispatch in self.dispatches.iter() {
+            let (id, _) = maybe_dispatch?;
+
+            if self.dispatch(&id)? {
+                offered += 1;
+            }
+        }
+
+        Ok(offered)
+    }
+}
+
+/// Periodically passes on offers that have run out. Does nothing unless dispatching is enabled.
+pub async fn run_dispatcher(dispatcher: Dispatcher) {
+    if !*ENABLED {
+        return;
+    }
+
+    info!("Dispatching help requests to volunteers");
+
+    let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let dispatcher = dispatcher.to_owned();
+
+        match tokio::task::spawn_blocking(move || dispatcher.dispatch_all()).await {
+            Ok(Ok(0)) => trace!("No help requests needed offering"),
+            Ok(Ok(offered)) => info!("Offered {offered} help requests to volunteers"),
+            Ok(Err(e)) => warn!("Failed to offer help requests to volunteers: {e:?}"),
+            Err(e) => warn!("Failed to offer help requests to volunteers: {e}"),
+        }
+    }
+}
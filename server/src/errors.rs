@@ -22,6 +22,26 @@ pub enum Error {
     DidntRequestHelp,
     RequestDoesntExist,
     RequestNotAcceptedByUser,
+    IllegalTransition {
+        from: &'static str,
+        transition: &'static str,
+    },
+    InvalidSchedule(&'static str),
+    InvalidProfile(&'static str),
+    RecurringRequestDoesntExist,
+    RecurringRequestFull,
+    NotOffered,
+    PictureTooLarge,
+    UnsupportedPicture(&'static str),
+    PictureDoesntExist,
+    TooManyPictures,
+    NoAfterPicture,
+    InvalidAddress(&'static str),
+    GeocoderUnavailable,
+    InvalidMessage(&'static str),
+    NotInThread,
+    InvalidContact(&'static str),
+    TransactionConflict,
     Json(serde_json::Error),
     Anyhow(anyhow::Error),
 }
@@ -92,6 +112,29 @@ impl Error {
             DidntRequestHelp => "You never requested help".into(),
             RequestDoesntExist => "That request doesn't exist".into(),
             RequestNotAcceptedByUser => "That request wasn't accepted by the user".into(),
+            IllegalTransition { from, transition } => {
+                format!("You can't {transition} a request that's {from}").into()
+            }
+            InvalidSchedule(reason) | InvalidProfile(reason) => (*reason).into(),
+            RecurringRequestDoesntExist => "That recurring request doesn't exist".into(),
+            RecurringRequestFull => {
+                "That recurring request already has as many volunteers as it needs".into()
+            }
+            NotOffered => "That request isn't offered to you".into(),
+            PictureTooLarge => "That picture is too large".into(),
+            UnsupportedPicture(reason) => (*reason).into(),
+            PictureDoesntExist => "That picture doesn't exist".into(),
+            TooManyPictures => "That's too many pictures".into(),
+            NoAfterPicture => "A picture of the finished work is needed".into(),
+            InvalidAddress(reason) => (*reason).into(),
+            GeocoderUnavailable => "Addresses can't be looked up right now".into(),
+            InvalidMessage(reason) => (*reason).into(),
+            NotInThread => {
+                "Only the senior and the volunteers helping them can see a request's messages"
+                    .into()
+            }
+            InvalidContact(reason) => (*reason).into(),
+            TransactionConflict => "The server is too busy to handle that right now".into(),
             Json(e) => format!("Failed to decode body: {e}").into(),
             Anyhow(e) => format!("Unexpected server error: {e}").into(),
         }
@@ -111,6 +154,22 @@ impl Error {
             DidntRequestHelp => StatusCode::CONFLICT,
             RequestDoesntExist => StatusCode::CONFLICT,
             RequestNotAcceptedByUser => StatusCode::CONFLICT,
+            IllegalTransition { .. } => StatusCode::CONFLICT,
+            InvalidSchedule(_) | InvalidProfile(_) => StatusCode::BAD_REQUEST,
+            RecurringRequestDoesntExist => StatusCode::CONFLICT,
+            RecurringRequestFull => StatusCode::CONFLICT,
+            NotOffered => StatusCode::CONFLICT,
+            PictureTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            UnsupportedPicture(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            PictureDoesntExist => StatusCode::CONFLICT,
+            TooManyPictures => StatusCode::BAD_REQUEST,
+            NoAfterPicture => StatusCode::BAD_REQUEST,
+            InvalidAddress(_) => StatusCode::BAD_REQUEST,
+            GeocoderUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            InvalidMessage(_) => StatusCode::BAD_REQUEST,
+            NotInThread => StatusCode::FORBIDDEN,
+            InvalidContact(_) => StatusCode::BAD_REQUEST,
+            TransactionConflict => StatusCode::SERVICE_UNAVAILABLE,
             Json(_) => StatusCode::BAD_REQUEST,
             Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -121,11 +180,31 @@ impl Error {
 
         match self {
             Anyhow(_) => error!("{}", self.description()),
-            InvalidToken | IncorrectPassword(_) => warn!("{}", self.description()),
-            NotSenior | NotVolunteer | RequestDoesntExist | RequestNotAcceptedByUser => {
+            InvalidToken | IncorrectPassword(_) | TransactionConflict | GeocoderUnavailable => {
+                warn!("{}", self.description())
+            }
+            NotSenior
+            | NotVolunteer
+            | RequestDoesntExist
+            | RequestNotAcceptedByUser
+            | IllegalTransition { .. }
+            | RecurringRequestDoesntExist
+            | RecurringRequestFull
+            | NotOffered
+            | PictureDoesntExist
+            | NotInThread => {
                 info!("{}", self.description())
             }
-            Json(_) => debug!("{}", self.description()),
+            Json(_)
+            | InvalidSchedule(_)
+            | InvalidProfile(_)
+            | PictureTooLarge
+            | UnsupportedPicture(_)
+            | TooManyPictures
+            | NoAfterPicture
+            | InvalidAddress(_)
+            | InvalidMessage(_)
+            | InvalidContact(_) => debug!("{}", self.description()),
             UsernameAlreadyExists(_)
             | UsernameDoesntExist(_)
             | AlreadyRequestedHelp
@@ -0,0 +1,282 @@
+//! Pushes changes to clients over a WebSocket at `/api/events`, so they don't have to keep polling. Whatever changes a request or posts a message publishes an event here once it's committed, and each connection passes on the ones its user should hear about.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, trace};
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::{
+    hyper::body::Bytes,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    authorization::authorize, clone, errors::Error, privacy::approximate_distance_to,
+    request_state::HelpRequestState, ArchivedUserType, HelpRequest, Location, UserDB,
+    VolunteerProfileDB,
+};
+
+/// How many events a slow connection can fall behind by before it misses some
+const CAPACITY: usize = 1024;
+
+/// How long a new connection has to send its authorization string
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Volunteers without a maximum distance in their profile hear about new requests this many meters away
+const NEARBY_DISTANCE: f64 = 10_000.;
+
+#[derive(Clone)]
+pub enum Event {
+    RequestUpdated {
+        id: String,
+        state: HelpRequestState,
+        recipients: Vec<String>,
+    },
+    NewRequest {
+        id: String,
+        location: Location,
+    },
+    RequestOffered {
+        id: String,
+        volunteer: String,
+        expires: i64,
+    },
+    NewMessage {
+        request: String,
+        id: String,
+        author: String,
+        recipients: Vec<String>,
+    },
+}
+
+/// Who a connection belongs to
+struct Listener {
+    username: String,
+    location: Location,
+    /// How far away new requests a volunteer hears about can be. Seniors don't hear about them.
+    nearby: Option<f64>,
+}
+
+impl Event {
+    /// What to send to the listener about the event, if they should hear about it
+    fn for_listener(&self, listener: &Listener) -> Option<serde_json::Value> {
+        match self {
+            Event::RequestUpdated {
+                id,
+                state,
+                recipients,
+            } if recipients.contains(&listener.username) => Some(json!({
+                "type": "RequestUpdated",
+                "id": id,
+                "state": state,
+            })),
+            Event::NewRequest { id, location } => {
+                let dist = approximate_distance_to(listener.location, *location);
+
+                match listener.nearby {
+                    Some(nearby) if dist <= nearby => Some(json!({
+                        "type": "NewRequest",
+                        "id": id,
+                        "dist": dist,
+                    })),
+                    _ => None,
+                }
+            }
+            Event::RequestOffered {
+                id,
+                volunteer,
+                expires,
+            } if *volunteer == listener.username => Some(json!({
+                "type": "RequestOffered",
+                "id": id,
+                "expires": expires,
+            })),
+            Event::NewMessage {
+                request,
+                id,
+                author,
+                recipients,
+            } if recipients.contains(&listener.username) && *author != listener.username => {
+                Some(json!({
+                    "type": "NewMessage",
+                    "request": request,
+                    "id": id,
+                    "author": author,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus(broadcast::Sender<Event>);
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus(broadcast::channel(CAPACITY).0)
+    }
+
+    fn publish(&self, event: Event) {
+        // It only fails when nobody is listening
+        if self.0.send(event).is_err() {
+            trace!("Nobody is listening for events");
+        }
+    }
+
+    /// Tells the senior and volunteers of a request that its state changed. `also` is anyone else who should hear about it, like volunteers who were helping with a request that was just cancelled.
+    pub fn request_updated(&self, id: &str, request: &HelpRequest, also: &[String]) {
+        let mut recipients = vec![request.username.to_owned()];
+        recipients.extend(request.state.volunteers().iter().cloned());
+        recipients.extend(also.iter().cloned());
+
+        self.publish(Event::RequestUpdated {
+            id: id.to_owned(),
+            state: request.state.to_owned(),
+            recipients,
+        });
+    }
+
+    /// Tells volunteers near a new request about it
+    pub fn new_request(&self, id: &str, location: Location) {
+        self.publish(Event::NewRequest {
+            id: id.to_owned(),
+            location,
+        });
+    }
+
+    /// Tells a volunteer a request was offered to them until `expires`
+    pub fn request_offered(&self, id: &str, volunteer: &str, expires: i64) {
+        self.publish(Event::RequestOffered {
+            id: id.to_owned(),
+            volunteer: volunteer.to_owned(),
+            expires,
+        });
+    }
+
+    /// Tells everyone in a request's thread except the author about a new message
+    pub fn new_message(&self, request: &str, id: &str, author: &str, recipients: Vec<String>) {
+        self.publish(Event::NewMessage {
+            request: request.to_owned(),
+            id: id.to_owned(),
+            author: author.to_owned(),
+            recipients,
+        });
+    }
+}
+
+fn listener(
+    bytes: &Bytes,
+    users: &UserDB,
+    profiles: &VolunteerProfileDB,
+) -> Result<Listener, Error> {
+    let username = authorize(bytes)?;
+
+    let user = users.get(&username)?.ok_or(Error::InvalidToken)?;
+
+    let nearby = match user.user_type {
+        ArchivedUserType::Volunteer(_) => Some(
+            profiles
+                .get(&username)?
+                .and_then(|profile| profile.max_distance.as_ref().copied())
+                .unwrap_or(NEARBY_DISTANCE),
+        ),
+        ArchivedUserType::Senior(_) => None,
+    };
+
+    Ok(Listener {
+        username,
+        location: user.location,
+        nearby,
+    })
+}
+
+pub fn events_filter(
+    user_db: &UserDB,
+    volunteer_profiles: &VolunteerProfileDB,
+    events: &EventBus,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "events")
+        .and(warp::ws())
+        .and(clone(user_db.to_owned()))
+        .and(clone(volunteer_profiles.to_owned()))
+        .and(clone(events.to_owned()))
+        .map(
+            |ws: Ws, users: UserDB, profiles: VolunteerProfileDB, events: EventBus| {
+                ws.on_upgrade(move |socket| send_events(socket, users, profiles, events))
+            },
+        )
+}
+
+/// The first message on the socket has to be `{ authorization: string }`, then events are sent until either side closes it.
+async fn send_events(
+    socket: WebSocket,
+    users: UserDB,
+    profiles: VolunteerProfileDB,
+    events: EventBus,
+) {
+    let (mut sender, mut receiver) = socket.split();
+
+    // Subscribed first so nothing that happens while authorizing is missed
+    let mut subscription = events.0.subscribe();
+
+    let bytes = match tokio::time::timeout(AUTHORIZATION_TIMEOUT, receiver.next()).await {
+        Ok(Some(Ok(message))) => Bytes::from(message.into_bytes()),
+        _ => return,
+    };
+
+    let listener = tokio::task::spawn_blocking(move || listener(&bytes, &users, &profiles)).await;
+
+    let listener = match listener {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            debug!("Refused an event connection: {e:?}");
+            let _ = sender
+                .send(Message::close_with(
+                    1008u16,
+                    "The authorization string was invalid",
+                ))
+                .await;
+            return;
+        }
+        Err(_) => return,
+    };
+
+    debug!("`{}` is listening for events", listener.username);
+
+    if sender
+        .send(Message::text(json!({ "type": "Ready" }).to_string()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            event = subscription.recv() => {
+                let message = match event {
+                    Ok(event) => match event.for_listener(&listener) {
+                        Some(v) => v,
+                        None => continue,
+                    },
+                    // The client should fetch everything again since it doesn't know what it missed
+                    Err(RecvError::Lagged(missed)) => json!({ "type": "Missed", "count": missed }),
+                    Err(RecvError::Closed) => break,
+                };
+
+                if sender.send(Message::text(message.to_string())).await.is_err() {
+                    break;
+                }
+            }
+            message = receiver.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => break,
+            },
+        }
+    }
+
+    debug!("`{}` stopped listening for events", listener.username);
+}
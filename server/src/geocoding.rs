@@ -0,0 +1,260 @@
+//! Works out where users are from their addresses. Clients don't send locations, since anyone could claim to live next to a senior. Which geocoder is used has to be set with `GEOCODER`: `nominatim` looks addresses up with a Nominatim server, and `gazetteer` looks them up in a local file. There's no default, since sending every user's address to a public server has to be a choice.
+
+use std::{collections::HashMap, env, fs, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{errors::Error, Location};
+
+/// How long to wait for a geocoding service before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait Geocoder: Send + Sync {
+    /// Where `address` is, or `None` if it can't be found
+    fn geocode(&self, address: &str) -> Result<Option<Location>, Error>;
+}
+
+/// Looks addresses up with a server that has Nominatim's search API, set with `NOMINATIM_URL`
+pub struct Nominatim {
+    url: String,
+    agent: ureq::Agent,
+}
+
+#[derive(Deserialize)]
+struct NominatimPlace {
+    lat: String,
+    lon: String,
+}
+
+impl Nominatim {
+    pub fn new(url: &str) -> Nominatim {
+        Nominatim {
+            url: url.trim_end_matches('/').to_owned(),
+            // Nominatim refuses requests that don't say who they're from
+            agent: ureq::AgentBuilder::new()
+                .timeout(TIMEOUT)
+                .user_agent(concat!("shovelmates/", env!("CARGO_PKG_VERSION")))
+                .build(),
+        }
+    }
+}
+
+impl Geocoder for Nominatim {
+    fn geocode(&self, address: &str) -> Result<Option<Location>, Error> {
+        let response = self
+            .agent
+            .get(&format!("{}/search", self.url))
+            .query("q", address)
+            .query("format", "jsonv2")
+            .query("limit", "1")
+            .call()
+            .map_err(|e| {
+                warn!("Failed to reach the geocoder: {e}");
+                Error::GeocoderUnavailable
+            })?
+            .into_string()
+            .map_err(|e| {
+                warn!("Failed to read the geocoder's response: {e}");
+                Error::GeocoderUnavailable
+            })?;
+
+        // Anything unexpected is the geocoder's fault, not the client's
+        let places: Vec<NominatimPlace> = serde_json::from_str(&response).map_err(|e| {
+            warn!("Failed to decode the geocoder's response: {e}");
+            Error::GeocoderUnavailable
+        })?;
+
+        let place = match places.first() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        match (place.lat.parse(), place.lon.parse()) {
+            (Ok(lat), Ok(lon)) => Ok(Some(Location(lat, lon))),
+            _ => {
+                warn!("The geocoder gave a location that isn't a number");
+                Err(Error::GeocoderUnavailable)
+            }
+        }
+    }
+}
+
+/// Looks addresses up in a file, for testing and for running without a network. Each line is an address followed by its latitude and longitude, separated by commas, and lines starting with `#` are ignored. Addresses match regardless of case, punctuation and spacing.
+pub struct Gazetteer {
+    places: HashMap<String, Location>,
+}
+
+impl Gazetteer {
+    pub fn load(path: &str) -> Result<Gazetteer, Error> {
+        let contents = fs::read_to_string(path).map_err(Error::unexpected)?;
+
+        let mut places = HashMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // Addresses have commas in them, so the coordinates are taken from the end
+            let mut fields = line.rsplitn(3, ',');
+
+            let (lon, lat, address) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(lon), Some(lat), Some(address)) => (lon, lat, address),
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Line {} of the gazetteer doesn't have an address, latitude and longitude",
+                        number + 1
+                    )))
+                }
+            };
+
+            let location = match (lat.trim().parse(), lon.trim().parse()) {
+                (Ok(lat), Ok(lon)) => Location(lat, lon),
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Line {} of the gazetteer has a location that isn't a number",
+                        number + 1
+                    )))
+                }
+            };
+
+            places.insert(normalize(address), location);
+        }
+
+        Ok(Gazetteer { places })
+    }
+}
+
+impl Geocoder for Gazetteer {
+    fn geocode(&self, address: &str) -> Result<Option<Location>, Error> {
+        Ok(self.places.get(&normalize(address)).copied())
+    }
+}
+
+/// Lowercase words separated by single spaces, without punctuation
+fn normalize(address: &str) -> String {
+    address
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The geocoder picked with `GEOCODER`
+#[derive(Clone)]
+pub struct Geocoding(Arc<dyn Geocoder>);
+
+impl Geocoding {
+    pub fn from_env() -> Geocoding {
+        let geocoder: Arc<dyn Geocoder> = match env::var("GEOCODER")
+            .expect("`GEOCODER` to be set to `nominatim` or `gazetteer`")
+            .as_str()
+        {
+            "nominatim" => {
+                let url = env::var("NOMINATIM_URL")
+                    .expect("`NOMINATIM_URL` to be set to the Nominatim server to use");
+
+                info!("Looking up addresses with {url}");
+
+                Arc::new(Nominatim::new(&url))
+            }
+            "gazetteer" => {
+                let path =
+                    env::var("GAZETTEER_PATH").unwrap_or_else(|_| "gazetteer.csv".to_owned());
+
+                info!("Looking up addresses in {path}");
+
+                Arc::new(Gazetteer::load(&path).expect("the gazetteer to be readable"))
+            }
+            _ => panic!("`GEOCODER` should be `nominatim` or `gazetteer`"),
+        };
+
+        Geocoding(geocoder)
+    }
+
+    /// Where a user with this address is. When the geocoder is down the request fails instead of guessing.
+    pub fn locate(&self, address: &str) -> Result<Location, Error> {
+        self.0
+            .geocode(address)?
+            .ok_or(Error::InvalidAddress("That address couldn't be found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A gazetteer file that's deleted when the test is done
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let path = env::temp_dir().join(format!("{name}-{}.csv", std::process::id()));
+            fs::write(&path, contents).unwrap();
+
+            TempFile(path)
+        }
+
+        fn load(&self) -> Result<Gazetteer, Error> {
+            Gazetteer::load(self.0.to_str().unwrap())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn looking_up_addresses() {
+        let file = TempFile::new(
+            "gazetteer-lookup",
+            "# address, latitude, longitude
+            123 Main St, Minneapolis, MN 55401, 44.97, -93.26
+
+            511 Kenwood Pkwy, Apt 2, Minneapolis, MN 55403,44.968,-93.298",
+        );
+        let geocoding = Geocoding(Arc::new(file.load().unwrap()));
+
+        let Location(lat, lon) = geocoding
+            .locate("123 MAIN ST.\nMinneapolis, MN  55401")
+            .unwrap();
+        assert_eq!((lat, lon), (44.97, -93.26));
+
+        let Location(lat, lon) = geocoding
+            .locate("511 Kenwood Pkwy\nApt 2\nMinneapolis MN 55403")
+            .unwrap();
+        assert_eq!((lat, lon), (44.968, -93.298));
+
+        assert!(matches!(
+            geocoding.locate("125 Main St, Minneapolis, MN 55401"),
+            Err(Error::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn malformed_gazetteers() {
+        let missing = TempFile::new("gazetteer-missing", "123 Main St, 44.97");
+        assert!(missing.load().is_err());
+
+        let not_a_number = TempFile::new("gazetteer-not-a-number", "123 Main St, north, west");
+        assert!(not_a_number.load().is_err());
+
+        assert!(Gazetteer::load("/nonexistent/gazetteer.csv").is_err());
+    }
+
+    #[test]
+    fn normalizing() {
+        assert_eq!(
+            normalize("  123 Main St.,\n Minneapolis, MN 55401-1234 "),
+            "123 main st minneapolis mn 55401 1234"
+        );
+    }
+}
@@ -1,10 +1,12 @@
-use base64::engine::general_purpose::URL_SAFE;
-use base64::Engine;
+use std::{collections::HashMap, env, num::NonZeroU32, time::Duration};
+
 use chrono::Utc;
-use log::{debug, error, info, trace};
-use rkyv::option::ArchivedOption;
-use serde::Deserialize;
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
+use serde::{de::IgnoredAny, Deserialize};
 use serde_json::json;
+use sled::transaction::ConflictableTransactionError;
+use ulid::Ulid;
 use warp::{
     body::bytes,
     hyper::{body::Bytes, Body},
@@ -13,13 +15,59 @@ use warp::{
 
 use crate::{
     authorization::authorize,
-    clone_dbs,
-    db::{Archived, Transactional},
+    blocking, clone, clone_dbs,
+    db::{Archived, Transaction, Transactional},
+    dispatch::Dispatcher,
     errors::Error,
-    extract_json, ArchivedUserType, HelpRequest, HelpRequestDB, HelpRequestState, User, UserDB,
+    events::EventBus,
+    extract_json,
+    notifications::{Notification, Notifier},
+    pictures::{hashes, PictureStore},
+    priority::Urgency,
+    request_state::{ArchivedHelpRequestState, Transition},
+    spatial_index::RequestIndex,
+    tasks::{Effort, Equipment, TaskType},
+    webhooks::{WebhookEvent, Webhooks},
+    ArchivedHelpRequest, ArchivedUser, ArchivedUserType, HelpRequest, HelpRequestDB,
+    HelpRequestState, Page, RecurringRequest, RecurringRequestDB, TimeWindow, User, UserDB,
     UserType,
 };
 
+/// How long a request without a time window can stay pending before it expires, set with `HELP_REQUEST_EXPIRY_HOURS`
+static REQUEST_LIFETIME: Lazy<Duration> = Lazy::new(|| {
+    let hours = match env::var("HELP_REQUEST_EXPIRY_HOURS") {
+        Ok(v) => v
+            .parse()
+            .expect("`HELP_REQUEST_EXPIRY_HOURS` should be a non-negative integer"),
+        Err(_) => 72,
+    };
+
+    Duration::from_secs(hours * 60 * 60)
+});
+
+/// How often pending requests are checked for expiry
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+impl ArchivedHelpRequest {
+    /// When the request expires if it's still pending. Scheduled requests expire once their window is over.
+    pub fn expiry_time(&self) -> i64 {
+        match self.window.as_ref() {
+            Some(window) => window.end,
+            None => self.creation_time + REQUEST_LIFETIME.as_millis() as i64,
+        }
+    }
+
+    /// When the work can be done, from the start of the window or right away until the request expires
+    pub fn work_span(&self) -> (i64, i64) {
+        let start = match self.window.as_ref() {
+            Some(window) => window.start,
+            None => self.creation_time,
+        };
+
+        (start, self.expiry_time())
+    }
+}
+
 fn help_request_endpoint(
     bytes: &Bytes,
     user_db: &UserDB,
@@ -40,30 +88,199 @@ fn help_request_endpoint(
     callback(bytes, username, user)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn help_requests_filters(
     user_db: &UserDB,
     help_requests: &HelpRequestDB,
+    recurring_requests: &RecurringRequestDB,
+    request_index: &RequestIndex,
+    dispatcher: &Dispatcher,
+    picture_store: &PictureStore,
+    events: &EventBus,
+    notifier: &Notifier,
+    webhooks: &Webhooks,
 ) -> impl Filter<Extract = (Result<Body, Error>,), Error = Rejection> + Clone {
     let request_help = warp::path!("api" / "request-help")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| request_help(&bytes, &users_db, &requests_db));
+        .and(clone(recurring_requests.to_owned()))
+        .and(clone(request_index.to_owned()))
+        .and(clone(dispatcher.to_owned()))
+        .and(clone(picture_store.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(webhooks.to_owned()))
+        .and_then(
+            move |bytes,
+                  users_db,
+                  requests_db,
+                  recurring_db,
+                  index,
+                  dispatcher,
+                  pictures,
+                  events,
+                  webhooks| {
+                blocking(move || {
+                    request_help(
+                        &bytes,
+                        &users_db,
+                        &requests_db,
+                        &recurring_db,
+                        &index,
+                        &dispatcher,
+                        &pictures,
+                        &events,
+                        &webhooks,
+                    )
+                })
+            },
+        );
 
     let get_requests = warp::path!("api" / "help-requests")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| {
-            help_request_endpoint(&bytes, &users_db, |_, username, user| {
-                debug!("`{username}` hit help-requests endpoint");
-                get_help_request(user, &requests_db)
+        .and_then(move |bytes, users_db, requests_db| {
+            blocking(move || {
+                help_request_endpoint(&bytes, &users_db, |_, username, user| {
+                    debug!("`{username}` hit help-requests endpoint");
+                    get_help_request(user, &requests_db)
+                })
             })
         });
 
     let delete_request = warp::path!("api" / "delete-help-request")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| {
-            delete_help_request(&bytes, &users_db, &requests_db)
+        .and(clone(request_index.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and(clone(webhooks.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, index, events, notifier, webhooks| {
+                blocking(move || {
+                    help_request_endpoint(&bytes, &users_db, |_, username, user| {
+                        debug!("`{username}` hit delete-help-request endpoint");
+                        delete_help_request(
+                            user,
+                            &users_db,
+                            &requests_db,
+                            &index,
+                            &events,
+                            &notifier,
+                            &webhooks,
+                        )
+                    })
+                })
+            },
+        );
+
+    let my_requests = warp::path!("api" / "my-requests")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and_then(move |bytes, users_db, requests_db| {
+            blocking(move || {
+                help_request_endpoint(&bytes, &users_db, |bytes, username, user| {
+                    debug!("`{username}` is getting their request history");
+                    my_requests(user, extract_json(bytes)?, &requests_db)
+                })
+            })
+        });
+
+    let confirm_completion = warp::path!("api" / "confirm-completion")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(request_index.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and(clone(webhooks.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, index, events, notifier, webhooks| {
+                blocking(move || {
+                    help_request_endpoint(&bytes, &users_db, |_, username, user| {
+                        debug!("`{username}` is confirming that their request was completed");
+                        transition_help_request(
+                            user,
+                            &users_db,
+                            &requests_db,
+                            &index,
+                            &events,
+                            &notifier,
+                            &webhooks,
+                            Transition::ConfirmCompletion,
+                        )
+                    })
+                })
+            },
+        );
+
+    let dispute_completion = warp::path!("api" / "dispute-completion")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(request_index.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and(clone(webhooks.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, index, events, notifier, webhooks| {
+                blocking(move || {
+                    help_request_endpoint(&bytes, &users_db, |_, username, user| {
+                        debug!("`{username}` is disputing that their request was completed");
+                        transition_help_request(
+                            user,
+                            &users_db,
+                            &requests_db,
+                            &index,
+                            &events,
+                            &notifier,
+                            &webhooks,
+                            Transition::DisputeCompletion,
+                        )
+                    })
+                })
+            },
+        );
+
+    let cancel_request = warp::path!("api" / "cancel-help-request")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(request_index.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and(clone(webhooks.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, index, events, notifier, webhooks| {
+                blocking(move || {
+                    help_request_endpoint(&bytes, &users_db, |_, username, user| {
+                        debug!("`{username}` is cancelling their request");
+                        transition_help_request(
+                            user,
+                            &users_db,
+                            &requests_db,
+                            &index,
+                            &events,
+                            &notifier,
+                            &webhooks,
+                            Transition::Cancel,
+                        )
+                    })
+                })
+            },
+        );
+
+    let cancel_recurring_request = warp::path!("api" / "cancel-recurring-request")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(recurring_requests.to_owned()))
+        .and_then(move |bytes, users_db, recurring_db| {
+            blocking(move || {
+                help_request_endpoint(&bytes, &users_db, |bytes, username, _| {
+                    debug!("`{username}` is cancelling a recurring request");
+                    cancel_recurring_request(
+                        username,
+                        extract_json::<RecurringRequestId>(bytes)?.id,
+                        &recurring_db,
+                    )
+                })
+            })
         });
 
     warp::post().and(
@@ -71,74 +288,318 @@ pub fn help_requests_filters(
             .or(get_requests)
             .unify()
             .or(delete_request)
+            .unify()
+            .or(my_requests)
+            .unify()
+            .or(confirm_completion)
+            .unify()
+            .or(dispute_completion)
+            .unify()
+            .or(cancel_request)
+            .unify()
+            .or(cancel_recurring_request)
             .unify(),
     )
 }
 
+/// How many of the senior's requests expired without anyone accepting them.
+pub fn unhelped_count(
+    requests: &[String],
+    requests_db: &Transaction<150, HelpRequest>,
+) -> Result<u32, ConflictableTransactionError<Error>> {
+    let mut unhelped = 0;
+
+    for id in requests {
+        if let Some(request) = requests_db.get(id)? {
+            if matches!(request.state, ArchivedHelpRequestState::Expired) {
+                unhelped += 1;
+            }
+        }
+    }
+
+    Ok(unhelped)
+}
+
+/// Creates a ULID for a help request. They sort by creation time like the old sequential IDs did, but the random part means they can't be guessed from each other.
+pub fn new_request_id(creation_time: i64) -> String {
+    Ulid::from_parts(creation_time as u64, rand::random()).to_string()
+}
+
+/// Gives a new ID to every request that still has one of the old sequential IDs, updating the seniors and volunteers that refer to it.
+pub fn migrate_legacy_request_ids(
+    users: &UserDB,
+    help_requests: &HelpRequestDB,
+) -> Result<(), Error> {
+    let mut new_ids = HashMap::new();
+
+    for maybe_request in help_requests.iter() {
+        let (id, request) = maybe_request?;
+
+        if Ulid::from_string(&id).is_err() {
+            new_ids.insert(id, new_request_id(request.creation_time));
+        }
+    }
+
+    if new_ids.is_empty() {
+        return Ok(());
+    }
+
+    let new_id = |id: &mut String| {
+        if let Some(new) = new_ids.get(id) {
+            id.clone_from(new);
+        }
+    };
+
+    let mut updated_users = Vec::new();
+
+    for maybe_user in users.iter() {
+        let (_, user) = maybe_user?;
+
+        let mut user = user.to_original();
+
+        match &mut user.user_type {
+            UserType::Senior(ids) | UserType::Volunteer(ids)
+                if ids.iter().any(|id| new_ids.contains_key(id)) =>
+            {
+                ids.iter_mut().for_each(new_id)
+            }
+            _ => continue,
+        }
+
+        updated_users.push(user);
+    }
+
+    (users, help_requests).transaction(|(users_db, requests_db)| {
+        for (old_id, new_id) in &new_ids {
+            if let Some(request) = requests_db.delete(old_id)? {
+                requests_db.add(new_id, &request.to_original())?;
+            }
+        }
+
+        for user in &updated_users {
+            users_db.add(&user.username, user)?;
+        }
+
+        Ok(())
+    })?;
+
+    info!(
+        "Migrated {} help requests and {} users to new request IDs",
+        new_ids.len(),
+        updated_users.len()
+    );
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct RequestHelpInfo {
-    picture: String,
+    /// The hashes of uploaded pictures
+    #[serde(default)]
+    pictures: Vec<String>,
+    /// Pictures used to be sent inline, which is refused so they aren't silently dropped
+    picture: Option<IgnoredAny>,
     notes: String,
+    volunteers_needed: Option<NonZeroU32>,
+    window: Option<TimeWindow>,
+    /// Makes the request recur with the same window this many days later
+    every_days: Option<NonZeroU32>,
+    #[serde(default = "default_task")]
+    task: TaskType,
+    #[serde(default = "default_effort")]
+    effort: Effort,
+    #[serde(default)]
+    equipment: Vec<Equipment>,
+    #[serde(default)]
+    urgency: Vec<Urgency>,
 }
 
+fn default_task() -> TaskType {
+    TaskType::Driveway
+}
+
+fn default_effort() -> Effort {
+    Effort::Medium
+}
+
+#[allow(clippy::too_many_arguments)]
 fn request_help(
     bytes: &Bytes,
     users: &UserDB,
     help_requests: &HelpRequestDB,
+    recurring_requests: &RecurringRequestDB,
+    request_index: &RequestIndex,
+    dispatcher: &Dispatcher,
+    picture_store: &PictureStore,
+    events: &EventBus,
+    webhooks: &Webhooks,
 ) -> Result<Body, Error> {
     let username = authorize(bytes)?;
     let request_help_info = extract_json::<RequestHelpInfo>(bytes)?;
 
+    if request_help_info.picture.is_some() {
+        return Err(Error::UnsupportedPicture(
+            "Pictures have to be uploaded with `upload-picture` and given in `pictures`",
+        ));
+    }
+
+    picture_store.check_all(&request_help_info.pictures, &username)?;
+
+    match (request_help_info.window, request_help_info.every_days) {
+        (Some(window), _) if window.end <= window.start => {
+            return Err(Error::InvalidSchedule(
+                "A time window has to end after it starts",
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(Error::InvalidSchedule(
+                "A recurring request needs a time window",
+            ))
+        }
+        _ => {}
+    }
+
     info!("{username} is requesting help");
 
-    (users, help_requests)
-        .transaction(move |(users_db, requests_db)| {
-            let user = match users_db.get(&username)? {
-                Some(v) => v,
-                None => {
-                    return Err(
-                        Error::msg("There exists a token for a user that doesn't exist").into(),
-                    )
+    let (id, location) = (
+        users,
+        help_requests,
+        recurring_requests,
+        webhooks.deliveries(),
+    )
+        .transaction(
+            move |(users_db, requests_db, recurring_db, deliveries_db)| {
+                let user = match users_db.get(&username)? {
+                    Some(v) => v,
+                    None => {
+                        return Err(Error::msg(
+                            "There exists a token for a user that doesn't exist",
+                        )
+                        .into())
+                    }
+                };
+
+                // Seniors can make a new request once their last one is finished
+                if let Some(id) = latest_request(&user) {
+                    if let Some(request) = requests_db.get(id)? {
+                        if !request.state.is_finished() {
+                            return Err(Error::AlreadyRequestedHelp.into());
+                        }
+                    }
                 }
-            };
-
-            if let ArchivedUserType::Senior(ArchivedOption::Some(_)) = &user.user_type {
-                return Err(Error::AlreadyRequestedHelp.into());
-            }
 
-            let mut user_de: User = user.to_original();
+                let mut user_de: User = user.to_original();
+
+                let creation_time = Utc::now().timestamp_millis();
+
+                let unhelped_before = match &user_de.user_type {
+                    UserType::Senior(requests) => unhelped_count(requests, &requests_db)?,
+                    _ => 0,
+                };
+
+                let recurring_request =
+                    match (request_help_info.window, request_help_info.every_days) {
+                        (Some(window), Some(every_days)) => {
+                            let recurring_id = new_request_id(creation_time);
+
+                            recurring_db.add(
+                                &recurring_id,
+                                &RecurringRequest {
+                                    pictures: request_help_info.pictures.to_owned(),
+                                    notes: request_help_info.notes.to_owned(),
+                                    username: user_de.username.to_owned(),
+                                    volunteers_needed: request_help_info
+                                        .volunteers_needed
+                                        .map_or(1, NonZeroU32::get),
+                                    location: user_de.location,
+                                    next_window: window.after_days(every_days.get()),
+                                    every_days: every_days.get(),
+                                    subscribers: Vec::new(),
+                                    task: request_help_info.task,
+                                    effort: request_help_info.effort,
+                                    equipment: request_help_info.equipment.to_owned(),
+                                    urgency: request_help_info.urgency.to_owned(),
+                                },
+                            )?;
+
+                            Some(recurring_id)
+                        }
+                        _ => None,
+                    };
+
+                let help_request = HelpRequest {
+                    pictures: request_help_info.pictures.to_owned(),
+                    notes: request_help_info.notes.to_owned(),
+                    creation_time,
+                    state: HelpRequestState::Pending,
+                    username: user_de.username,
+                    volunteers_needed: request_help_info
+                        .volunteers_needed
+                        .map_or(1, NonZeroU32::get),
+                    location: user_de.location,
+                    finished_time: None,
+                    window: request_help_info.window,
+                    recurring_request,
+                    task: request_help_info.task,
+                    effort: request_help_info.effort,
+                    equipment: request_help_info.equipment.to_owned(),
+                    urgency: request_help_info.urgency.to_owned(),
+                    unhelped_before,
+                    after_pictures: Vec::new(),
+                };
+
+                let id = new_request_id(creation_time);
+
+                requests_db.add(&id, &help_request)?;
+
+                // Transfer ownership back
+                user_de.username = help_request.username;
+
+                if let UserType::Senior(requests) = &mut user_de.user_type {
+                    requests.push(id.to_owned());
+                }
 
-            let help_request = HelpRequest {
-                picture: request_help_info.picture.to_owned(),
-                notes: request_help_info.notes.to_owned(),
-                creation_time: Utc::now().timestamp_millis(),
-                state: HelpRequestState::Pending,
-                username: user_de.username,
-            };
+                users_db.add(&user.username, &user_de)?;
 
-            let id = URL_SAFE.encode(requests_db.generate_id()?.to_le_bytes());
+                webhooks.request_event(
+                    &users_db,
+                    &requests_db,
+                    &deliveries_db,
+                    WebhookEvent::Created,
+                    &id,
+                )?;
 
-            requests_db.add(&id, &help_request)?;
+                info!(
+                    "`{}` successfully created a request for help",
+                    user.username
+                );
 
-            // Transfer ownership back
-            user_de.username = help_request.username;
+                Ok((id, help_request.location))
+            },
+        )?;
 
-            user_de.user_type = UserType::Senior(Some(id));
+    request_index.insert(&id, location);
+    events.new_request(&id, location);
 
-            users_db.add(&user.username, &user_de)?;
+    // The request was made either way, so failing to offer it only means volunteers have to find it themselves
+    if let Err(e) = dispatcher.dispatch(&id) {
+        warn!("Failed to offer a new help request to a volunteer: {e:?}");
+    }
 
-            info!(
-                "`{}` successfully created a request for help",
-                user.username
-            );
+    Ok(Body::from("{}"))
+}
 
-            Ok(Body::from("{}"))
-        })
-        .map_err(|e| e.into())
+/// The ID of the senior's most recent request, which is the only one that can still be unfinished.
+fn latest_request(user: &ArchivedUser) -> Option<&str> {
+    match &user.user_type {
+        ArchivedUserType::Senior(requests) => requests.last().map(|id| id.as_str()),
+        _ => None,
+    }
 }
 
 fn get_help_request(user: Archived<User>, help_requests: &HelpRequestDB) -> Result<Body, Error> {
-    if let ArchivedUserType::Senior(ArchivedOption::Some(id)) = &user.user_type {
+    if let Some(id) = latest_request(&user) {
         let help_request = match help_requests.get(id)? {
             Some(v) => v,
             None => return Err(Error::msg(
@@ -152,70 +613,297 @@ fn get_help_request(user: Archived<User>, help_requests: &HelpRequestDB) -> Resu
         );
 
         Ok(Body::from(serde_json::to_string(&json!({
-            "picture": &*help_request.picture,
+            "pictures": hashes(&help_request.pictures),
             "notes": &*help_request.notes,
             "creationTime": help_request.creation_time,
             "state": help_request.state.to_json(),
+            "volunteersNeeded": help_request.volunteers_needed,
+            "window": help_request.window.as_ref(),
+            "recurringRequest": help_request.recurring_request.as_ref().map(|id| id.as_str()),
+            "task": help_request.task,
+            "effort": help_request.effort,
+            "equipment": &*help_request.equipment,
+            "urgency": &*help_request.urgency,
+            "afterPictures": hashes(&help_request.after_pictures),
         }))?))
     } else {
         Err(Error::DidntRequestHelp)
     }
 }
 
-fn delete_help_request(
-    bytes: &Bytes,
-    user_db: &UserDB,
+#[allow(clippy::too_many_arguments)]
+fn transition_help_request(
+    user: Archived<User>,
+    users: &UserDB,
     help_requests: &HelpRequestDB,
+    request_index: &RequestIndex,
+    events: &EventBus,
+    notifier: &Notifier,
+    webhooks: &Webhooks,
+    transition: Transition,
 ) -> Result<Body, Error> {
-    let username = authorize(bytes)?;
+    let id = latest_request(&user).ok_or(Error::DidntRequestHelp)?;
+
+    // Volunteers who are dropped by cancelling still need to hear about it
+    let (request, previous_volunteers) =
+        (users, help_requests, webhooks.deliveries()).transaction(
+            |(users_db, requests_db, deliveries_db)| {
+            let mut request = match requests_db.get(id)? {
+                Some(v) => v.to_original(),
+                None => return Err(Error::msg(
+                    "The ID for the help request stored in the server doesn't exist in the database",
+                )
+                .into()),
+            };
 
-    (user_db, help_requests)
-        .transaction(|(users_db, requests_db)| {
-            debug!("`{username}` hit delete-help-request endpoint");
+            let previous_volunteers = request.state.volunteers().to_vec();
 
-            let user = match users_db.get(&username)? {
-                Some(v) => v,
-                None => {
-                    return Err(Error::msg(
-                        "There exists a token for a username that doesn't exist",
-                    )
-                    .into())
+            request.transition(transition)?;
+
+            requests_db.add(id, &request)?;
+
+            // The request is no longer one the volunteers have accepted
+            if matches!(request.state, HelpRequestState::Cancelled) {
+                for volunteer in &previous_volunteers {
+                    let mut volunteer = match users_db.get(volunteer)? {
+                        Some(v) => v.to_original(),
+                        None => continue,
+                    };
+
+                    if let UserType::Volunteer(accepted) = &mut volunteer.user_type {
+                        accepted.retain(|accepted_id| accepted_id != id);
+                    }
+
+                    users_db.add(&volunteer.username, &volunteer)?;
                 }
-            };
+            }
 
-            match &user.user_type {
-                ArchivedUserType::Senior(maybe_id) => match maybe_id {
-                    ArchivedOption::Some(id) => {
-                        if requests_db.delete(id)?.is_none() {
-                            return Err(Error::msg(
-                                "The ID for the help request stored in the server doesn't exist in the database",
-                            ).into());
-                        };
+            if matches!(request.state, HelpRequestState::CompletedBy(_)) {
+                webhooks.request_event(
+                    &users_db,
+                    &requests_db,
+                    &deliveries_db,
+                    WebhookEvent::Completed,
+                    id,
+                )?;
+            }
 
-                        let mut user_de = user.to_original();
+            Ok((request, previous_volunteers))
+        },
+        )?;
 
-                        user_de.user_type = UserType::Senior(None);
+    if request.state.is_finished() {
+        request_index.remove(id, request.location);
+    }
 
-                        users_db.add(&user.username, &user_de)?;
+    events.request_updated(id, &request, &previous_volunteers);
 
-                        info!(
-                            "`{}` successfully deleted their help request",
-                            user.username
-                        );
+    if matches!(request.state, HelpRequestState::Cancelled) {
+        for volunteer in &previous_volunteers {
+            if let Err(e) = notifier.notify(volunteer, Notification::cancelled(id)) {
+                warn!("Failed to tell a volunteer a request they accepted was cancelled: {e:?}");
+            }
+        }
+    }
 
-                        Ok(Body::from("Successfully deleted help request"))
-                    }
-                    ArchivedOption::None => {
-                        debug!(
-                            "`{}` tried to delete their help request but there was nothing to delete",
-                            user.username
-                        );
+    info!(
+        "`{}` changed the state of their help request",
+        user.username
+    );
 
-                        Ok(Body::from("There was nothing to delete"))
-                    }
-                },
-                _ => Err(Error::NotSenior.into()),
+    Ok(Body::from("{}"))
+}
+
+/// Periodically expires requests that have been pending for longer than `REQUEST_LIFETIME`.
+pub async fn expire_requests(
+    help_requests: HelpRequestDB,
+    request_index: RequestIndex,
+    events: EventBus,
+    notifier: Notifier,
+) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let help_requests = help_requests.to_owned();
+        let request_index = request_index.to_owned();
+        let events = events.to_owned();
+        let notifier = notifier.to_owned();
+
+        match tokio::task::spawn_blocking(move || {
+            expire_stale_requests(&help_requests, &request_index, &events, &notifier)
+        })
+        .await
+        {
+            Ok(Ok(0)) => trace!("No help requests expired"),
+            Ok(Ok(expired)) => info!("Expired {expired} help requests"),
+            Ok(Err(e)) => warn!("Failed to expire help requests: {e:?}"),
+            Err(e) => warn!("Failed to expire help requests: {e}"),
+        }
+    }
+}
+
+fn expire_stale_requests(
+    help_requests: &HelpRequestDB,
+    request_index: &RequestIndex,
+    events: &EventBus,
+    notifier: &Notifier,
+) -> Result<usize, Error> {
+    let now = Utc::now().timestamp_millis();
+    let mut expired = 0;
+
+    for maybe_request in help_requests.iter() {
+        let (id, request) = maybe_request?;
+
+        if !matches!(request.state, ArchivedHelpRequestState::Pending)
+            || request.expiry_time() >= now
+        {
+            continue;
+        }
+
+        let expired_request = help_requests.transaction(|requests_db| {
+            let mut request = match requests_db.get(&id)? {
+                Some(v) => v.to_original(),
+                None => return Ok(None),
+            };
+
+            // It may have been accepted since it was read
+            if request.transition(Transition::Expire).is_err() {
+                return Ok(None);
+            }
+
+            requests_db.add(&id, &request)?;
+
+            Ok(Some(request))
+        })?;
+
+        if let Some(request) = expired_request {
+            request_index.remove(&id, request.location);
+            events.request_updated(&id, &request, &[]);
+            expired += 1;
+
+            if let Err(e) = notifier.notify(&request.username, Notification::expired(&id)) {
+                warn!("Failed to tell a senior their request expired: {e:?}");
             }
+        }
+    }
+
+    Ok(expired)
+}
+
+/// Cancels the senior's unfinished request. It used to be removed entirely, but now it's kept in their history like any other cancelled request.
+fn delete_help_request(
+    user: Archived<User>,
+    users: &UserDB,
+    help_requests: &HelpRequestDB,
+    request_index: &RequestIndex,
+    events: &EventBus,
+    notifier: &Notifier,
+    webhooks: &Webhooks,
+) -> Result<Body, Error> {
+    let unfinished = match latest_request(&user) {
+        Some(id) => help_requests
+            .get(id)?
+            .is_some_and(|request| !request.state.is_finished()),
+        None => false,
+    };
+
+    if !unfinished {
+        debug!(
+            "`{}` tried to delete their help request but there was nothing to delete",
+            user.username
+        );
+
+        return Ok(Body::from("There was nothing to delete"));
+    }
+
+    transition_help_request(
+        user,
+        users,
+        help_requests,
+        request_index,
+        events,
+        notifier,
+        webhooks,
+        Transition::Cancel,
+    )
+    .map(|_| Body::from("Successfully deleted help request"))
+}
+
+/// Every request the senior has made, newest first. The cursor is the ID of the last request of the previous page.
+fn my_requests(
+    user: Archived<User>,
+    page: Page<String>,
+    help_requests: &HelpRequestDB,
+) -> Result<Body, Error> {
+    let requests = match &user.user_type {
+        ArchivedUserType::Senior(requests) => requests,
+        _ => return Err(Error::NotSenior),
+    };
+
+    let start = match &page.cursor {
+        Some(cursor) => requests
+            .iter()
+            .rev()
+            .position(|id| id.as_str() == cursor)
+            .map_or(requests.len(), |i| i + 1),
+        None => 0,
+    };
+
+    let history = requests
+        .iter()
+        .rev()
+        .skip(start)
+        .take(page.limit())
+        .map(|id| {
+            let request = help_requests.get(id)?.ok_or_else(|| {
+                Error::msg("A help request in a senior's history doesn't exist in the database")
+            })?;
+
+            Ok(json!({
+                "id": id.as_str(),
+                "notes": &*request.notes,
+                "creationTime": request.creation_time,
+                "finishedTime": request.finished_time.as_ref(),
+                "state": request.state.to_json(),
+                "volunteersNeeded": request.volunteers_needed,
+                "window": request.window.as_ref(),
+                "recurringRequest": request.recurring_request.as_ref().map(|id| id.as_str()),
+                "task": request.task,
+                "effort": request.effort,
+                "equipment": &*request.equipment,
+                "urgency": &*request.urgency,
+            }))
         })
-        .map_err(|e| e.into())
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(Body::from(serde_json::to_string(&history)?))
+}
+
+#[derive(Deserialize)]
+struct RecurringRequestId {
+    id: String,
+}
+
+/// Stops new help requests from being made from a recurring request. Ones already made aren't affected.
+fn cancel_recurring_request(
+    username: String,
+    id: String,
+    recurring_requests: &RecurringRequestDB,
+) -> Result<Body, Error> {
+    recurring_requests.transaction(|recurring_db| {
+        match recurring_db.get(&id)? {
+            Some(recurring) if recurring.username == username => {}
+            _ => return Err(Error::RecurringRequestDoesntExist.into()),
+        }
+
+        recurring_db.delete(&id)?;
+
+        Ok(())
+    })?;
+
+    info!("`{username}` cancelled a recurring request");
+
+    Ok(Body::from("{}"))
 }
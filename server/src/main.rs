@@ -1,33 +1,71 @@
 mod accounts;
+mod address;
 mod authorization;
+mod availability;
 mod db;
+mod dispatch;
 mod errors;
+mod events;
+mod geocoding;
 mod help_requests;
+mod messaging;
+mod notifications;
+mod pictures;
+mod priority;
+mod privacy;
+mod recurring_requests;
+mod request_state;
+mod route_plan;
+mod schema;
+mod spatial_index;
+mod tasks;
 mod volunteering;
+mod webhooks;
 
 use std::convert::Infallible;
 
-use db::{Archived, Db};
+use chrono::Utc;
+use db::{migrate_sled_to_sqlite, Archived, Db, Storage};
 use geo::algorithm::geodesic_distance::GeodesicDistance;
 use log::info;
 use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::json;
 use warp::{
     filters::any,
     hyper::{body::Bytes, Body, Response},
-    Filter,
+    Filter, Rejection,
 };
 
 use crate::{
-    accounts::accounts_filters, errors::Error, help_requests::help_requests_filters,
+    accounts::accounts_filters,
+    address::Address,
+    availability::{is_available, TravelMode, WeeklyWindow},
+    dispatch::{run_dispatcher, DispatchDB, Dispatcher},
+    errors::Error,
+    events::{events_filter, EventBus},
+    geocoding::Geocoding,
+    help_requests::{expire_requests, help_requests_filters, migrate_legacy_request_ids},
+    messaging::{messaging_filters, MessageDB, MessageReadDB},
+    notifications::{
+        notifications_filters, run_outbox, NotificationPreferencesDB, Notifier, OutboxDB,
+    },
+    pictures::{picture_files, pictures_filters, PictureDB, PictureStore},
+    priority::Urgency,
+    privacy::AddressAccessDB,
+    recurring_requests::schedule_recurring_requests,
+    request_state::{HelpRequestState, Transition},
+    schema::SchemaVersionDB,
+    spatial_index::{RequestIndex, VolunteerIndex},
+    tasks::{Effort, Equipment, TaskType},
     volunteering::volunteering_filters,
+    webhooks::{run_webhooks, WebhookDeliveryDB, Webhooks},
 };
 
 #[derive(Serialize, Deserialize, Clone, Archive, RkyvSerialize, RkyvDeserialize)]
 pub enum UserType {
     Volunteer(Vec<String>),
-    Senior(Option<String>),
+    /// Every request the senior has made, oldest first. Only the last one can still be unfinished.
+    Senior(Vec<String>),
 }
 
 #[derive(Clone, Copy, Archive, RkyvSerialize, RkyvDeserialize, Debug)]
@@ -58,7 +96,7 @@ impl From<Location> for (f64, f64) {
 pub struct User {
     username: String,
     name: String,
-    address: String,
+    address: Address,
     location: Location,
     user_type: UserType,
     salt: [u8; 32],
@@ -67,37 +105,121 @@ pub struct User {
 
 pub type UserDB = Db<250, User>;
 
-#[derive(Clone, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
-pub enum HelpRequestState {
-    Pending,
-    AcceptedBy(String),
-    MarkedCompletedBy(String),
-}
-
-impl ArchivedHelpRequestState {
-    fn to_json(&self) -> serde_json::Value {
-        match self {
-            ArchivedHelpRequestState::Pending => json!("Pending"),
-            ArchivedHelpRequestState::AcceptedBy(user) => json!({ "AcceptedBy": &**user }),
-            ArchivedHelpRequestState::MarkedCompletedBy(user) => {
-                json!({ "MarkedCompletedBy": &**user })
-            }
-        }
-    }
-}
-
 #[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
 #[repr(C)]
 pub struct HelpRequest {
-    picture: String,
+    /// The hashes of the senior's pictures in the picture store
+    pictures: Vec<String>,
     notes: String,
     creation_time: i64,
     state: HelpRequestState,
     username: String,
+    volunteers_needed: u32,
+    /// Where the senior was when they made the request
+    location: Location,
+    /// When the request was completed, cancelled or expired
+    finished_time: Option<i64>,
+    /// When the senior wants the work done
+    window: Option<TimeWindow>,
+    /// The ID of the recurring request this was made from
+    recurring_request: Option<String>,
+    task: TaskType,
+    effort: Effort,
+    /// What a volunteer needs to bring
+    equipment: Vec<Equipment>,
+    urgency: Vec<Urgency>,
+    /// How many of the senior's earlier requests expired without anyone accepting them
+    unhelped_before: u32,
+    /// Pictures of the finished work from the volunteer who marked it completed
+    after_pictures: Vec<String>,
 }
 
 pub type HelpRequestDB = Db<150, HelpRequest>;
 
+/// A span of time in milliseconds since the UNIX epoch
+#[derive(Clone, Copy, Archive, RkyvSerialize, RkyvDeserialize, Serialize, Deserialize, Debug)]
+#[archive(as = "Self")]
+pub struct TimeWindow {
+    start: i64,
+    end: i64,
+}
+
+impl TimeWindow {
+    /// The same window `days` days later
+    pub fn after_days(self, days: u32) -> TimeWindow {
+        let shift = i64::from(days) * 24 * 60 * 60 * 1000;
+
+        TimeWindow {
+            start: self.start + shift,
+            end: self.end + shift,
+        }
+    }
+}
+
+/// A template that a new help request is made from every `every_days` days
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct RecurringRequest {
+    pictures: Vec<String>,
+    notes: String,
+    username: String,
+    volunteers_needed: u32,
+    location: Location,
+    /// The window of the next help request that will be made
+    next_window: TimeWindow,
+    every_days: u32,
+    /// Volunteers who accept every help request made from this
+    subscribers: Vec<String>,
+    task: TaskType,
+    effort: Effort,
+    equipment: Vec<Equipment>,
+    urgency: Vec<Urgency>,
+}
+
+pub type RecurringRequestDB = Db<200, RecurringRequest>;
+
+/// What a volunteer is able to do, and where and when. Volunteers who haven't set one are shown every request.
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolunteerProfile {
+    tasks: Vec<TaskType>,
+    max_effort: Effort,
+    equipment: Vec<Equipment>,
+    /// The farthest they'll travel in meters
+    #[serde(default)]
+    max_distance: Option<f64>,
+    #[serde(default)]
+    travel_mode: TravelMode,
+    /// When each week they're free to help. Empty means any time.
+    #[serde(default)]
+    availability: Vec<WeeklyWindow>,
+}
+
+pub type VolunteerProfileDB = Db<100, VolunteerProfile>;
+
+impl ArchivedVolunteerProfile {
+    /// Whether the volunteer does this kind of task, at this size, and has everything it needs.
+    pub fn can_do(&self, request: &ArchivedHelpRequest) -> bool {
+        self.tasks.contains(&request.task)
+            && request.effort <= self.max_effort
+            && request
+                .equipment
+                .iter()
+                .all(|equipment| self.equipment.contains(equipment))
+    }
+
+    /// Whether the volunteer could get to work `dist` meters away that can be done from `start` until `end`, at a time they're available.
+    pub fn can_reach(&self, dist: f64, (start, end): (i64, i64), now: i64) -> bool {
+        if matches!(self.max_distance.as_ref(), Some(max) if dist > *max) {
+            return false;
+        }
+
+        let arrival = now + self.travel_mode.travel_time(dist);
+
+        is_available(&self.availability, start.max(arrival), end)
+    }
+}
+
 pub fn distance_meters(coord1: Location, coord2: Location) -> f64 {
     geo::Point::from(coord1).geodesic_distance(&coord2.into())
 }
@@ -109,11 +231,19 @@ impl HelpRequest {
         })
     }
 
-    pub fn distance_meters(&self, coordinates: Location, user_db: &UserDB) -> Result<f64, Error> {
-        let senior = self.get_user(user_db)?;
-        let senior_coord = senior.location;
+    pub fn distance_meters(&self, coordinates: Location) -> f64 {
+        distance_meters(coordinates, self.location)
+    }
+
+    /// Moves the request to the state it's in after `transition`, noting when it finishes.
+    pub fn transition(&mut self, transition: Transition) -> Result<(), Error> {
+        self.state = self.state.transition(transition)?;
+
+        if self.state.is_finished() {
+            self.finished_time = Some(Utc::now().timestamp_millis());
+        }
 
-        Ok(distance_meters(coordinates, senior_coord))
+        Ok(())
     }
 }
 
@@ -124,11 +254,8 @@ impl ArchivedHelpRequest {
         })
     }
 
-    pub fn distance_meters(&self, coordinates: Location, user_db: &UserDB) -> Result<f64, Error> {
-        let senior = self.get_user(user_db)?;
-        let senior_coord = senior.location;
-
-        Ok(distance_meters(coordinates, senior_coord))
+    pub fn distance_meters(&self, coordinates: Location) -> f64 {
+        distance_meters(coordinates, self.location)
     }
 }
 
@@ -140,6 +267,22 @@ trait InfallibleDeserialize<T>: RkyvDeserialize<T, rkyv::Infallible> {
 
 impl<V, T: RkyvDeserialize<V, rkyv::Infallible>> InfallibleDeserialize<V> for T {}
 
+/// The most entries a list endpoint will return at once.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// The optional `cursor` and `limit` fields accepted by list endpoints. The cursor is the last entry of the previous page.
+#[derive(Deserialize)]
+pub struct Page<C> {
+    cursor: Option<C>,
+    limit: Option<usize>,
+}
+
+impl<C> Page<C> {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+}
+
 pub fn extract_json<T: DeserializeOwned>(bytes: &Bytes) -> Result<T, Error> {
     serde_json::from_slice(bytes.as_ref()).map_err(Error::Json)
 }
@@ -148,6 +291,16 @@ pub fn clone<V: Clone + Send>(v: V) -> impl Filter<Extract = (V,), Error = Infal
     any::any().map(move || v.to_owned())
 }
 
+/// Runs a handler on Tokio's blocking thread pool, so slow disk access or password hashing doesn't stall the threads serving every other connection.
+pub async fn blocking<F>(f: F) -> Result<Result<Body, Error>, Rejection>
+where
+    F: FnOnce() -> Result<Body, Error> + Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(Error::unexpected(e))))
+}
+
 pub fn clone_dbs(
     user_db: &UserDB,
     requests_db: &HelpRequestDB,
@@ -159,17 +312,148 @@ pub fn clone_dbs(
 async fn main() {
     pretty_env_logger::init();
 
-    let db = sled::open("db").expect("the DB to open properly");
-    let users_db: UserDB = Db::open(&db, "users");
-    let help_requests_db: HelpRequestDB = Db::open(&db, "help-requests");
+    let args = std::env::args().collect::<Vec<_>>();
 
-    let accounts = accounts_filters(&users_db);
-    let help_requests = help_requests_filters(&users_db, &help_requests_db);
-    let volunteering = volunteering_filters(&users_db, &help_requests_db);
+    if let [_, command, sled_path, sqlite_path] = args.as_slice() {
+        if command == "migrate-sled-to-sqlite" {
+            migrate_sled_to_sqlite(sled_path, sqlite_path).expect("the migration to succeed");
+            return;
+        }
+    }
+
+    let storage = Storage::from_env();
+    let users_db: UserDB = Db::open(&storage, "users");
+    let help_requests_db: HelpRequestDB = Db::open(&storage, "help-requests");
+    let recurring_requests_db: RecurringRequestDB = Db::open(&storage, "recurring-requests");
+    let volunteer_profiles_db: VolunteerProfileDB = Db::open(&storage, "volunteer-profiles");
+    let dispatches_db: DispatchDB = Db::open(&storage, "dispatches");
+    let pictures_db: PictureDB = Db::open(&storage, "pictures");
+    let messages_db: MessageDB = Db::open(&storage, "messages");
+    let message_reads_db: MessageReadDB = Db::open(&storage, "message-reads");
+    let notification_preferences_db: NotificationPreferencesDB =
+        Db::open(&storage, "notification-preferences");
+    let outbox_db: OutboxDB = Db::open(&storage, "notification-outbox");
+    let webhook_deliveries_db: WebhookDeliveryDB = Db::open(&storage, "webhook-deliveries");
+    let address_accesses_db: AddressAccessDB = Db::open(&storage, "address-accesses");
+    let schema_versions_db: SchemaVersionDB = Db::open(&storage, "schema-versions");
+
+    let picture_store = PictureStore::open(&pictures_db);
+
+    schema::migrate(
+        &schema_versions_db,
+        &users_db,
+        &help_requests_db,
+        &picture_store,
+    )
+    .expect("the databases to migrate to the latest layout");
+
+    migrate_legacy_request_ids(&users_db, &help_requests_db)
+        .expect("the old request IDs to migrate");
+
+    let request_index =
+        RequestIndex::build(&help_requests_db).expect("the help request locations to be indexed");
+
+    let volunteer_index =
+        VolunteerIndex::build(&users_db).expect("the volunteer locations to be indexed");
+
+    let events = EventBus::new();
+
+    let notifier = Notifier::from_env(&notification_preferences_db, &outbox_db);
+
+    tokio::spawn(run_outbox(notifier.to_owned()));
+
+    let dispatcher = Dispatcher::new(
+        &users_db,
+        &help_requests_db,
+        &volunteer_profiles_db,
+        &dispatches_db,
+        &volunteer_index,
+        &events,
+        &notifier,
+    );
 
-    let get = warp::get().and(warp::fs::dir("../frontend/build"));
+    tokio::spawn(run_dispatcher(dispatcher.to_owned()));
+
+    let webhooks = Webhooks::from_env(&webhook_deliveries_db);
+
+    tokio::spawn(run_webhooks(webhooks.to_owned()));
+
+    tokio::spawn(expire_requests(
+        help_requests_db.to_owned(),
+        request_index.to_owned(),
+        events.to_owned(),
+        notifier.to_owned(),
+    ));
+
+    tokio::spawn(schedule_recurring_requests(
+        users_db.to_owned(),
+        help_requests_db.to_owned(),
+        recurring_requests_db.to_owned(),
+        volunteer_profiles_db.to_owned(),
+        request_index.to_owned(),
+        dispatcher.to_owned(),
+        events.to_owned(),
+        notifier.to_owned(),
+        webhooks.to_owned(),
+    ));
+
+    let geocoding = Geocoding::from_env();
+
+    let accounts = accounts_filters(&users_db, &geocoding, &volunteer_index);
+    let help_requests = help_requests_filters(
+        &users_db,
+        &help_requests_db,
+        &recurring_requests_db,
+        &request_index,
+        &dispatcher,
+        &picture_store,
+        &events,
+        &notifier,
+        &webhooks,
+    );
+    let volunteering = volunteering_filters(
+        &users_db,
+        &help_requests_db,
+        &recurring_requests_db,
+        &volunteer_profiles_db,
+        &address_accesses_db,
+        &request_index,
+        &dispatcher,
+        &picture_store,
+        &events,
+        &notifier,
+        &webhooks,
+    );
+
+    let pictures = pictures_filters(&users_db, &picture_store);
+    let notifications = notifications_filters(&users_db, &notifier);
+    let messaging = messaging_filters(
+        &users_db,
+        &help_requests_db,
+        &messages_db,
+        &message_reads_db,
+        &events,
+    );
+
+    let get = warp::get().and(
+        events_filter(&users_db, &volunteer_profiles_db, &events)
+            .or(picture_files(&picture_store))
+            .or(warp::fs::dir("../frontend/build")),
+    );
     let post = warp::post()
-        .and(accounts.or(help_requests).unify().or(volunteering).unify())
+        .and(
+            accounts
+                .or(help_requests)
+                .unify()
+                .or(volunteering)
+                .unify()
+                .or(pictures)
+                .unify()
+                .or(messaging)
+                .unify()
+                .or(notifications)
+                .unify(),
+        )
         .map(|v: Result<Body, Error>| match v {
             Ok(v) => Response::builder()
                 .status(200)
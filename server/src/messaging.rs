@@ -0,0 +1,281 @@
+//! Lets a senior and the volunteers helping them talk about a request, like where the shovel is. Each request has one thread, which only its senior and the volunteers who've accepted it and haven't finished can see.
+
+use chrono::Utc;
+use log::debug;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::Deserialize;
+use serde_json::json;
+use ulid::Ulid;
+use warp::{
+    body::bytes,
+    hyper::{body::Bytes, Body},
+    Filter, Rejection,
+};
+
+use crate::{
+    authorization::authorize,
+    blocking, clone, clone_dbs,
+    db::{Db, Transactional},
+    errors::Error,
+    events::EventBus,
+    extract_json,
+    request_state::ArchivedHelpRequestState,
+    ArchivedHelpRequest, HelpRequestDB, Page, UserDB,
+};
+
+/// The longest a message can be in characters
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct Message {
+    author: String,
+    text: String,
+    time: i64,
+}
+
+/// Keyed by `<request ID>/<message ID>`, so each thread's messages are next to each other and in the order they were sent
+pub type MessageDB = Db<250, Message>;
+
+/// The ID of the last message each user has read in a thread, keyed by `<request ID>/<username>`
+pub type MessageReadDB = Db<64, String>;
+
+/// Whether the user is the request's senior or one of the volunteers helping with it
+fn can_see_thread(request: &ArchivedHelpRequest, username: &str) -> bool {
+    request.username == username || request.state.is_helped_by(username)
+}
+
+fn messaging_endpoint(
+    bytes: &Bytes,
+    user_db: &UserDB,
+    callback: impl FnOnce(&Bytes, String) -> Result<Body, Error>,
+) -> Result<Body, Error> {
+    let username = authorize(bytes)?;
+
+    if user_db.get(&username)?.is_none() {
+        return Err(Error::msg("Oofy token"));
+    }
+
+    callback(bytes, username)
+}
+
+pub fn messaging_filters(
+    user_db: &UserDB,
+    help_requests: &HelpRequestDB,
+    messages: &MessageDB,
+    message_reads: &MessageReadDB,
+    events: &EventBus,
+) -> impl Filter<Extract = (Result<Body, Error>,), Error = Rejection> + Clone {
+    let post_message = warp::path!("api" / "post-message")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(messages.to_owned()))
+        .and(clone(events.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, messages_db, events| {
+            blocking(move || {
+                messaging_endpoint(&bytes, &users_db, |bytes, username| {
+                    debug!("`{username}` is posting a message");
+                    post_message(
+                        username,
+                        extract_json(bytes)?,
+                        &requests_db,
+                        &messages_db,
+                        &events,
+                    )
+                })
+            })
+        });
+
+    let messages_list = warp::path!("api" / "messages")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(messages.to_owned()))
+        .and(clone(message_reads.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, messages_db, reads_db| {
+            blocking(move || {
+                messaging_endpoint(&bytes, &users_db, |bytes, username| {
+                    debug!("`{username}` is reading messages");
+                    list_messages(
+                        username,
+                        extract_json(bytes)?,
+                        &requests_db,
+                        &messages_db,
+                        &reads_db,
+                    )
+                })
+            })
+        });
+
+    let mark_read = warp::path!("api" / "mark-messages-read")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(message_reads.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, reads_db| {
+            blocking(move || {
+                messaging_endpoint(&bytes, &users_db, |bytes, username| {
+                    debug!("`{username}` is marking messages as read");
+                    mark_read(username, extract_json(bytes)?, &requests_db, &reads_db)
+                })
+            })
+        });
+
+    post_message.or(messages_list).unify().or(mark_read).unify()
+}
+
+#[derive(Deserialize)]
+struct PostMessageInfo {
+    /// The ID of the help request
+    id: String,
+    text: String,
+}
+
+fn post_message(
+    username: String,
+    info: PostMessageInfo,
+    help_requests: &HelpRequestDB,
+    messages: &MessageDB,
+    events: &EventBus,
+) -> Result<Body, Error> {
+    let text = info.text.trim();
+
+    if text.is_empty() {
+        return Err(Error::InvalidMessage("A message can't be empty"));
+    }
+
+    if text.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(Error::InvalidMessage(
+            "A message can be at most 2000 characters",
+        ));
+    }
+
+    let time = Utc::now().timestamp_millis();
+    let message_id = Ulid::from_parts(time as u64, rand::random()).to_string();
+
+    let recipients = (help_requests, messages).transaction(|(requests_db, messages_db)| {
+        let recipients = match requests_db.get(&info.id)? {
+            Some(request) if can_see_thread(&request, &username) => {
+                let mut recipients = vec![request.username.to_string()];
+
+                if let ArchivedHelpRequestState::AcceptedBy(volunteers)
+                | ArchivedHelpRequestState::MarkedCompletedBy(volunteers) = &request.state
+                {
+                    recipients.extend(volunteers.iter().map(|v| v.to_string()));
+                }
+
+                recipients
+            }
+            Some(_) => return Err(Error::NotInThread.into()),
+            None => return Err(Error::RequestDoesntExist.into()),
+        };
+
+        messages_db.add(
+            &format!("{}/{message_id}", info.id),
+            &Message {
+                author: username.to_owned(),
+                text: text.to_owned(),
+                time,
+            },
+        )?;
+
+        Ok(recipients)
+    })?;
+
+    events.new_message(&info.id, &message_id, &username, recipients);
+
+    Ok(Body::from(serde_json::to_string(
+        &json!({ "id": message_id }),
+    )?))
+}
+
+#[derive(Deserialize)]
+struct MessagesInfo {
+    /// The ID of the help request
+    id: String,
+    /// The cursor is the ID of the last message of the previous page
+    #[serde(flatten)]
+    page: Page<String>,
+}
+
+/// The messages of a request's thread, oldest first
+fn list_messages(
+    username: String,
+    info: MessagesInfo,
+    help_requests: &HelpRequestDB,
+    messages: &MessageDB,
+    message_reads: &MessageReadDB,
+) -> Result<Body, Error> {
+    match help_requests.get(&info.id)? {
+        Some(request) if can_see_thread(&request, &username) => {}
+        Some(_) => return Err(Error::NotInThread),
+        None => return Err(Error::RequestDoesntExist),
+    }
+
+    let read_up_to = message_reads
+        .get(&format!("{}/{username}", info.id))?
+        .map(|id| id.to_string());
+
+    let prefix = format!("{}/", info.id);
+    let cursor = info.page.cursor.as_deref().unwrap_or("");
+
+    let mut thread = Vec::new();
+
+    let page = messages
+        .scan_prefix(&prefix)
+        .skip_while(
+            |maybe_message| matches!(maybe_message, Ok((key, _)) if &key[prefix.len()..] <= cursor),
+        )
+        .take(info.page.limit());
+
+    for maybe_message in page {
+        let (key, message) = maybe_message?;
+        let id = &key[prefix.len()..];
+
+        let unread =
+            message.author != username && !matches!(&read_up_to, Some(read) if read.as_str() >= id);
+
+        thread.push(json!({
+            "id": id,
+            "author": &*message.author,
+            "text": &*message.text,
+            "time": message.time,
+            "unread": unread,
+        }));
+    }
+
+    Ok(Body::from(serde_json::to_string(&thread)?))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MarkReadInfo {
+    /// The ID of the help request
+    id: String,
+    /// The ID of the last message that was read
+    up_to: String,
+}
+
+/// Marks every message up to and including `up_to` as read. Messages that were already read stay read.
+fn mark_read(
+    username: String,
+    info: MarkReadInfo,
+    help_requests: &HelpRequestDB,
+    message_reads: &MessageReadDB,
+) -> Result<Body, Error> {
+    match help_requests.get(&info.id)? {
+        Some(request) if can_see_thread(&request, &username) => {}
+        Some(_) => return Err(Error::NotInThread),
+        None => return Err(Error::RequestDoesntExist),
+    }
+
+    let key = format!("{}/{username}", info.id);
+
+    message_reads.transaction(|reads_db| {
+        if !matches!(reads_db.get(&key)?, Some(read) if read.as_str() >= info.up_to.as_str()) {
+            reads_db.add(&key, &info.up_to)?;
+        }
+
+        Ok(())
+    })?;
+
+    Ok(Body::from("{}"))
+}
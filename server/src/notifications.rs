@@ -0,0 +1,648 @@
+//! Tells users when something happens to their requests, like a volunteer accepting one, even when they don't have the app open. Which channels are used is set with `NOTIFICATION_CHANNELS`, a comma separated list of `push`, `email`, `sms` and `log`, and each user chooses how they can be reached and what they don't want to hear about. Notifications wait in an outbox until they're sent, so ones that fail are tried again later, even after a restart.
+
+use std::{env, fs::OpenOptions, io::Write, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use lettre::{
+    message::Mailbox,
+    transport::smtp::{authentication::Credentials, SmtpTransport},
+    Address, Transport,
+};
+use log::{debug, info, trace, warn};
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ulid::Ulid;
+use warp::{
+    body::bytes,
+    hyper::{body::Bytes, Body},
+    Filter, Rejection,
+};
+
+use crate::{
+    authorization::authorize,
+    blocking, clone,
+    db::{Db, Transactional},
+    errors::Error,
+    extract_json, UserDB,
+};
+
+/// How long to wait for a push service or SMS gateway before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the outbox is checked for notifications to send
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Notifications that still fail after this many tries are dropped
+const MAX_ATTEMPTS: u32 = 8;
+
+/// How long to wait before trying a notification again for the first time. The wait doubles with every failure.
+const RETRY_DELAY: i64 = 60 * 1000;
+
+/// What happened, which users can choose not to hear about
+#[derive(
+    Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum NotificationKind {
+    /// Told to the senior
+    Accepted,
+    /// Told to the senior
+    Withdrawn,
+    /// Told to the senior
+    MarkedCompleted,
+    /// Told to the volunteers who had accepted it
+    Cancelled,
+    /// Told to the senior
+    Expired,
+    /// Told to the volunteer a request is offered to
+    Offered,
+}
+
+impl NotificationKind {
+    fn title(&self) -> &'static str {
+        match self {
+            NotificationKind::Accepted => "Your request was accepted",
+            NotificationKind::Withdrawn => "A volunteer can't help anymore",
+            NotificationKind::MarkedCompleted => "Your request was completed",
+            NotificationKind::Cancelled => "A request was cancelled",
+            NotificationKind::Expired => "Your request expired",
+            NotificationKind::Offered => "A request was offered to you",
+        }
+    }
+}
+
+pub struct Notification {
+    kind: NotificationKind,
+    /// The ID of the help request
+    request: String,
+    text: String,
+}
+
+impl Notification {
+    pub fn accepted(request: &str, volunteer: &str) -> Notification {
+        Notification {
+            kind: NotificationKind::Accepted,
+            request: request.to_owned(),
+            text: format!("{volunteer} accepted your request for help."),
+        }
+    }
+
+    pub fn withdrawn(request: &str, volunteer: &str) -> Notification {
+        Notification {
+            kind: NotificationKind::Withdrawn,
+            request: request.to_owned(),
+            text: format!("{volunteer} can't help with your request anymore."),
+        }
+    }
+
+    pub fn marked_completed(request: &str, volunteer: &str) -> Notification {
+        Notification {
+            kind: NotificationKind::MarkedCompleted,
+            request: request.to_owned(),
+            text: format!("{volunteer} finished your request. Please confirm that it was done."),
+        }
+    }
+
+    pub fn cancelled(request: &str) -> Notification {
+        Notification {
+            kind: NotificationKind::Cancelled,
+            request: request.to_owned(),
+            text: "A request you accepted was cancelled, so you don't need to go.".to_owned(),
+        }
+    }
+
+    pub fn expired(request: &str) -> Notification {
+        Notification {
+            kind: NotificationKind::Expired,
+            request: request.to_owned(),
+            text: "Nobody accepted your request in time. You can make it again.".to_owned(),
+        }
+    }
+
+    pub fn offered(request: &str) -> Notification {
+        Notification {
+            kind: NotificationKind::Offered,
+            request: request.to_owned(),
+            text: "Someone nearby needs help. Accept or decline the request before the offer runs out."
+                .to_owned(),
+        }
+    }
+}
+
+/// How a user can be reached, and what they don't want to hear about. Users who haven't set any are only reached on channels that don't need contact details.
+#[derive(Clone, Default, Archive, RkyvSerialize, RkyvDeserialize, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPreferences {
+    #[serde(default)]
+    email: Option<String>,
+    /// In international format, like +16125550123
+    #[serde(default)]
+    phone: Option<String>,
+    /// The token the push service gave the user's device
+    #[serde(default)]
+    push_token: Option<String>,
+    #[serde(default)]
+    muted: Vec<NotificationKind>,
+}
+
+pub type NotificationPreferencesDB = Db<100, NotificationPreferences>;
+
+/// A notification waiting to be sent on one channel, keyed by a ULID so the oldest are sent first
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct OutboxEntry {
+    channel: String,
+    /// Where to send it on the channel, like an email address
+    to: String,
+    /// The ID of the help request
+    request: String,
+    title: String,
+    text: String,
+    attempts: u32,
+    /// When it can be tried again, in milliseconds since the UNIX epoch
+    next_attempt: i64,
+}
+
+pub type OutboxDB = Db<250, OutboxEntry>;
+
+pub trait Channel: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Where to send the user's notifications on this channel, or `None` if they can't be reached on it
+    fn address(
+        &self,
+        username: &str,
+        preferences: Option<&ArchivedNotificationPreferences>,
+    ) -> Option<String>;
+
+    fn send(&self, to: &str, request: &str, title: &str, text: &str) -> Result<(), Error>;
+}
+
+/// Sends push notifications through a service with FCM's legacy HTTP API, `https://fcm.googleapis.com/fcm/send` unless `PUSH_URL` is set, with the server key set with `PUSH_KEY`
+pub struct Push {
+    url: String,
+    key: String,
+    agent: ureq::Agent,
+}
+
+impl Channel for Push {
+    fn name(&self) -> &'static str {
+        "push"
+    }
+
+    fn address(
+        &self,
+        _: &str,
+        preferences: Option<&ArchivedNotificationPreferences>,
+    ) -> Option<String> {
+        preferences?.push_token.as_ref().map(|v| v.to_string())
+    }
+
+    fn send(&self, to: &str, request: &str, title: &str, text: &str) -> Result<(), Error> {
+        self.agent
+            .post(&self.url)
+            .set("Authorization", &format!("key={}", self.key))
+            .set("Content-Type", "application/json")
+            .send_string(
+                &json!({
+                    "to": to,
+                    "notification": { "title": title, "body": text },
+                    "data": { "request": request },
+                })
+                .to_string(),
+            )
+            .map_err(Error::unexpected)?;
+
+        Ok(())
+    }
+}
+
+/// Sends emails through the SMTP server set with `SMTP_HOST`, from the address set with `SMTP_FROM`. `SMTP_SECURITY` is `starttls`, the default, `tls`, or `none` for a server on the same machine, and `SMTP_USERNAME` and `SMTP_PASSWORD` are used to log in if they're set.
+pub struct Email {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Email {
+    fn from_env() -> Email {
+        let host = env::var("SMTP_HOST").expect("`SMTP_HOST` should be set to send emails");
+
+        let mut builder = match env::var("SMTP_SECURITY").as_deref().unwrap_or("starttls") {
+            "starttls" => SmtpTransport::starttls_relay(&host).map_err(Error::unexpected),
+            "tls" => SmtpTransport::relay(&host).map_err(Error::unexpected),
+            "none" => Ok(SmtpTransport::builder_dangerous(&host)),
+            _ => panic!("`SMTP_SECURITY` should be `starttls`, `tls` or `none`"),
+        }
+        .expect("the SMTP server to be usable")
+        .timeout(Some(TIMEOUT));
+
+        if let Ok(port) = env::var("SMTP_PORT") {
+            builder = builder.port(port.parse().expect("`SMTP_PORT` should be a port number"));
+        }
+
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Email {
+            transport: builder.build(),
+            from: env::var("SMTP_FROM")
+                .expect("`SMTP_FROM` should be set to send emails")
+                .parse()
+                .expect("`SMTP_FROM` should be an email address"),
+        }
+    }
+}
+
+impl Channel for Email {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn address(
+        &self,
+        _: &str,
+        preferences: Option<&ArchivedNotificationPreferences>,
+    ) -> Option<String> {
+        preferences?.email.as_ref().map(|v| v.to_string())
+    }
+
+    fn send(&self, to: &str, _: &str, title: &str, text: &str) -> Result<(), Error> {
+        let message = lettre::Message::builder()
+            .from(self.from.to_owned())
+            .to(to.parse().map_err(Error::unexpected)?)
+            .subject(title)
+            .body(text.to_owned())
+            .map_err(Error::unexpected)?;
+
+        self.transport.send(&message).map_err(Error::unexpected)?;
+
+        Ok(())
+    }
+}
+
+/// Sends text messages by posting `{ to, text }` to the gateway set with `SMS_URL`, with `SMS_TOKEN` as a bearer token if it's set
+pub struct Sms {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl Channel for Sms {
+    fn name(&self) -> &'static str {
+        "sms"
+    }
+
+    fn address(
+        &self,
+        _: &str,
+        preferences: Option<&ArchivedNotificationPreferences>,
+    ) -> Option<String> {
+        preferences?.phone.as_ref().map(|v| v.to_string())
+    }
+
+    fn send(&self, to: &str, _: &str, title: &str, text: &str) -> Result<(), Error> {
+        let mut request = self.agent.post(&self.url);
+
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+
+        request
+            .set("Content-Type", "application/json")
+            .send_string(&json!({ "to": to, "text": format!("{title}: {text}") }).to_string())
+            .map_err(Error::unexpected)?;
+
+        Ok(())
+    }
+}
+
+/// Writes notifications to the file set with `NOTIFICATION_LOG` as lines of JSON, or to the log under the `notifications` target, for testing. Every user can be reached on it.
+pub struct LogChannel {
+    path: Option<PathBuf>,
+}
+
+impl Channel for LogChannel {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn address(
+        &self,
+        username: &str,
+        _: Option<&ArchivedNotificationPreferences>,
+    ) -> Option<String> {
+        Some(username.to_owned())
+    }
+
+    fn send(&self, to: &str, request: &str, title: &str, text: &str) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(v) => v,
+            None => {
+                info!(target: "notifications", "To `{to}` about request {request}: {title}: {text}");
+                return Ok(());
+            }
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::unexpected)?;
+
+        let line = json!({
+            "to": to,
+            "request": request,
+            "title": title,
+            "text": text,
+            "time": Utc::now().timestamp_millis(),
+        });
+
+        writeln!(file, "{line}").map_err(Error::unexpected)?;
+
+        Ok(())
+    }
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+}
+
+#[derive(Clone)]
+pub struct Notifier {
+    channels: Arc<Vec<Box<dyn Channel>>>,
+    preferences: NotificationPreferencesDB,
+    outbox: OutboxDB,
+}
+
+impl Notifier {
+    pub fn from_env(preferences: &NotificationPreferencesDB, outbox: &OutboxDB) -> Notifier {
+        let names = env::var("NOTIFICATION_CHANNELS").unwrap_or_default();
+
+        let mut channels: Vec<Box<dyn Channel>> = Vec::new();
+
+        for name in names.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            channels.push(match name {
+                "push" => Box::new(Push {
+                    url: env::var("PUSH_URL")
+                        .unwrap_or_else(|_| "https://fcm.googleapis.com/fcm/send".to_owned()),
+                    key: env::var("PUSH_KEY").expect("`PUSH_KEY` should be set to send pushes"),
+                    agent: agent(),
+                }),
+                "email" => Box::new(Email::from_env()),
+                "sms" => Box::new(Sms {
+                    url: env::var("SMS_URL").expect("`SMS_URL` should be set to send texts"),
+                    token: env::var("SMS_TOKEN").ok(),
+                    agent: agent(),
+                }),
+                "log" => Box::new(LogChannel {
+                    path: env::var("NOTIFICATION_LOG").ok().map(PathBuf::from),
+                }),
+                _ => panic!(
+                    "`NOTIFICATION_CHANNELS` should be a comma separated list of `push`, `email`, `sms` and `log`"
+                ),
+            });
+
+            info!("Sending notifications with {name}");
+        }
+
+        Notifier {
+            channels: Arc::new(channels),
+            preferences: preferences.to_owned(),
+            outbox: outbox.to_owned(),
+        }
+    }
+
+    /// Puts the notification in the outbox for every channel the user can be reached on, unless they muted it
+    pub fn notify(&self, username: &str, notification: Notification) -> Result<(), Error> {
+        if self.channels.is_empty() {
+            return Ok(());
+        }
+
+        let preferences = self.preferences.get(username)?;
+
+        if matches!(&preferences, Some(preferences) if preferences.muted.contains(&notification.kind))
+        {
+            trace!("`{username}` muted a notification");
+            return Ok(());
+        }
+
+        let now = Utc::now().timestamp_millis();
+
+        let entries = self
+            .channels
+            .iter()
+            .filter_map(|channel| {
+                let to = channel.address(username, preferences.as_deref())?;
+
+                Some((
+                    Ulid::from_parts(now as u64, rand::random()).to_string(),
+                    OutboxEntry {
+                        channel: channel.name().to_owned(),
+                        to,
+                        request: notification.request.to_owned(),
+                        title: notification.kind.title().to_owned(),
+                        text: notification.text.to_owned(),
+                        attempts: 0,
+                        next_attempt: now,
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            debug!("`{username}` can't be reached on any channel");
+            return Ok(());
+        }
+
+        self.outbox.transaction(|outbox_db| {
+            for (id, entry) in &entries {
+                outbox_db.add(id, entry)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Tries to send every notification in the outbox that's due. Returns how many were sent.
+    fn send_due(&self) -> Result<usize, Error> {
+        let now = Utc::now().timestamp_millis();
+        let mut sent = 0;
+
+        for maybe_entry in self.outbox.iter() {
+            let (id, entry) = maybe_entry?;
+
+            if entry.next_attempt > now {
+                continue;
+            }
+
+            let channel = self
+                .channels
+                .iter()
+                .find(|channel| channel.name() == entry.channel.as_str());
+
+            let result = match channel {
+                Some(channel) => channel.send(&entry.to, &entry.request, &entry.title, &entry.text),
+                None => Err(Error::msg(format!(
+                    "The {} channel isn't used anymore",
+                    entry.channel
+                ))),
+            };
+
+            let mut entry = entry.to_original();
+
+            // A channel that isn't used anymore won't start working by waiting
+            let give_up = channel.is_none() || entry.attempts + 1 >= MAX_ATTEMPTS;
+
+            entry.attempts += 1;
+            entry.next_attempt = now + RETRY_DELAY * 2_i64.pow(entry.attempts - 1);
+
+            self.outbox.transaction(|outbox_db| {
+                if result.is_ok() || give_up {
+                    outbox_db.delete(&id)?;
+                } else {
+                    outbox_db.add(&id, &entry)?;
+                }
+
+                Ok(())
+            })?;
+
+            match result {
+                Ok(()) => sent += 1,
+                Err(e) if give_up => {
+                    warn!(
+                        "Gave up on sending a notification by {}: {e:?}",
+                        entry.channel
+                    )
+                }
+                Err(e) => warn!(
+                    "Failed to send a notification by {}, trying again later: {e:?}",
+                    entry.channel
+                ),
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+/// Periodically sends the notifications in the outbox.
+pub async fn run_outbox(notifier: Notifier) {
+    let mut interval = tokio::time::interval(OUTBOX_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let notifier = notifier.to_owned();
+
+        match tokio::task::spawn_blocking(move || notifier.send_due()).await {
+            Ok(Ok(0)) => trace!("No notifications were sent"),
+            Ok(Ok(sent)) => info!("Sent {sent} notifications"),
+            Ok(Err(e)) => warn!("Failed to send notifications: {e:?}"),
+            Err(e) => warn!("Failed to send notifications: {e}"),
+        }
+    }
+}
+
+fn notifications_endpoint(
+    bytes: &Bytes,
+    user_db: &UserDB,
+    callback: impl FnOnce(&Bytes, String) -> Result<Body, Error>,
+) -> Result<Body, Error> {
+    let username = authorize(bytes)?;
+
+    if user_db.get(&username)?.is_none() {
+        return Err(Error::msg("Oofy token"));
+    }
+
+    callback(bytes, username)
+}
+
+pub fn notifications_filters(
+    user_db: &UserDB,
+    notifier: &Notifier,
+) -> impl Filter<Extract = (Result<Body, Error>,), Error = Rejection> + Clone {
+    let get_preferences = warp::path!("api" / "notification-preferences")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(notifier.preferences.to_owned()))
+        .and_then(move |bytes, users_db, preferences_db| {
+            blocking(move || {
+                notifications_endpoint(&bytes, &users_db, |_, username| {
+                    debug!("`{username}` is getting their notification preferences");
+                    get_preferences(username, &preferences_db)
+                })
+            })
+        });
+
+    let set_preferences = warp::path!("api" / "set-notification-preferences")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(notifier.preferences.to_owned()))
+        .and_then(move |bytes, users_db, preferences_db| {
+            blocking(move || {
+                notifications_endpoint(&bytes, &users_db, |bytes, username| {
+                    debug!("`{username}` is setting their notification preferences");
+                    set_preferences(username, extract_json(bytes)?, &preferences_db)
+                })
+            })
+        });
+
+    get_preferences.or(set_preferences).unify()
+}
+
+fn get_preferences(
+    username: String,
+    preferences: &NotificationPreferencesDB,
+) -> Result<Body, Error> {
+    let preferences = preferences
+        .get(&username)?
+        .map_or_else(NotificationPreferences::default, |v| v.to_original());
+
+    Ok(Body::from(serde_json::to_string(&preferences)?))
+}
+
+fn is_phone_number(phone: &str) -> bool {
+    match phone.strip_prefix('+') {
+        Some(digits) => {
+            (8..=15).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+fn set_preferences(
+    username: String,
+    mut info: NotificationPreferences,
+    preferences: &NotificationPreferencesDB,
+) -> Result<Body, Error> {
+    for contact in [&mut info.email, &mut info.phone, &mut info.push_token] {
+        *contact = contact
+            .take()
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty());
+    }
+
+    if matches!(&info.email, Some(email) if email.parse::<Address>().is_err()) {
+        return Err(Error::InvalidContact("That email address isn't valid"));
+    }
+
+    if matches!(&info.phone, Some(phone) if !is_phone_number(phone)) {
+        return Err(Error::InvalidContact(
+            "The phone number has to be in international format, like +16125550123",
+        ));
+    }
+
+    preferences.transaction(|preferences_db| {
+        preferences_db.add(&username, &info)?;
+
+        Ok(())
+    })?;
+
+    Ok(Body::from("{}"))
+}
@@ -0,0 +1,423 @@
+//! Uploaded pictures are kept as files named by the hash of their contents instead of in the databases, so reading a help request doesn't mean reading its picture too. Every picture is decoded and encoded again before it's stored, which checks that it really is an image and drops its metadata, including where it was taken.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::Engine;
+use chrono::Utc;
+use image::{
+    codecs::jpeg::JpegEncoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+};
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use rkyv::{
+    string::ArchivedString, vec::ArchivedVec, Archive, Deserialize as RkyvDeserialize,
+    Serialize as RkyvSerialize,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha3::Digest;
+use warp::{
+    body::{bytes, content_length_limit},
+    http::header,
+    hyper::{Body, Response},
+    Filter, Rejection,
+};
+
+use crate::{
+    authorization::authorize, blocking, clone, db::Db, db::Transactional, errors::Error,
+    extract_json, UserDB,
+};
+
+/// The largest picture that can be uploaded in bytes, set with `MAX_PICTURE_BYTES`
+static MAX_PICTURE_BYTES: Lazy<usize> = Lazy::new(|| match env::var("MAX_PICTURE_BYTES") {
+    Ok(v) => v
+        .parse()
+        .expect("`MAX_PICTURE_BYTES` should be a non-negative integer"),
+    Err(_) => 10 * 1024 * 1024,
+});
+
+/// The most pictures a help request or its completion can have
+const MAX_PICTURES: usize = 10;
+
+/// Pictures wider or taller than this in pixels are refused, so a small file can't decode into an enormous image
+const MAX_DIMENSION: u32 = 8192;
+
+/// Thumbnails fit in a square this many pixels wide
+const THUMBNAIL_SIZE: u32 = 256;
+
+const JPEG_QUALITY: u8 = 90;
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// What's known about a stored picture, keyed by its hash and who uploaded it as `<hash>/<uploader>`. Everyone who uploads the same picture gets their own record of it.
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct Picture {
+    mime_type: String,
+    /// The hash of the thumbnail, which is always a JPEG
+    thumbnail: String,
+    upload_time: i64,
+}
+
+pub type PictureDB = Db<100, Picture>;
+
+/// The files of the pictures, and what's known about them
+#[derive(Clone)]
+pub struct PictureStore {
+    dir: Arc<PathBuf>,
+    pictures: PictureDB,
+}
+
+impl PictureStore {
+    /// Opens the store in the directory set with `PICTURE_PATH`, `pictures` by default.
+    pub fn open(pictures: &PictureDB) -> PictureStore {
+        let dir = PathBuf::from(env::var("PICTURE_PATH").unwrap_or_else(|_| "pictures".to_owned()));
+
+        fs::create_dir_all(&dir).expect("the picture directory to be created");
+
+        info!("Storing pictures in {}", dir.display());
+
+        PictureStore {
+            dir: Arc::new(dir),
+            pictures: pictures.to_owned(),
+        }
+    }
+
+    /// Checks, cleans up and stores a picture, returning its hash. Storing the same picture twice gives the same hash.
+    pub fn store(&self, data: &[u8], mime_type: &str, uploader: &str) -> Result<String, Error> {
+        if data.len() > *MAX_PICTURE_BYTES {
+            return Err(Error::PictureTooLarge);
+        }
+
+        let format = match mime_type {
+            "image/jpeg" => ImageFormat::Jpeg,
+            "image/png" => ImageFormat::Png,
+            "image/webp" => ImageFormat::WebP,
+            _ => {
+                return Err(Error::UnsupportedPicture(
+                    "Pictures have to be JPEG, PNG or WebP",
+                ))
+            }
+        };
+
+        if image::guess_format(data).ok() != Some(format) {
+            return Err(Error::UnsupportedPicture(
+                "The picture isn't the type it says it is",
+            ));
+        }
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_DIMENSION);
+        limits.max_image_height = Some(MAX_DIMENSION);
+
+        let mut reader = ImageReader::with_format(Cursor::new(data), format);
+        reader.limits(limits);
+
+        let invalid = |_| Error::UnsupportedPicture("The picture couldn't be read");
+
+        let mut decoder = reader.into_decoder().map_err(invalid)?;
+        // Cameras often store pictures sideways and say which way is up in the metadata that's about to be dropped
+        let orientation = decoder.orientation().map_err(invalid)?;
+        let mut picture = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+        picture.apply_orientation(orientation);
+
+        // JPEGs are kept as JPEGs, and everything else as PNGs so nothing is lost
+        let (stored, stored_type) = match format {
+            ImageFormat::Jpeg => (encode_jpeg(&picture, JPEG_QUALITY)?, "image/jpeg"),
+            _ => {
+                let mut png = Vec::new();
+                picture
+                    .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                    .map_err(Error::unexpected)?;
+
+                (png, "image/png")
+            }
+        };
+
+        let thumbnail = encode_jpeg(
+            &picture.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+            THUMBNAIL_QUALITY,
+        )?;
+
+        let hash = self.write_file(&stored)?;
+        let thumbnail_hash = self.write_file(&thumbnail)?;
+
+        let info = Picture {
+            mime_type: stored_type.to_owned(),
+            thumbnail: thumbnail_hash,
+            upload_time: Utc::now().timestamp_millis(),
+        };
+
+        let key = format!("{hash}/{uploader}");
+
+        self.pictures.transaction(|pictures_db| {
+            if pictures_db.get(&key)?.is_none() {
+                pictures_db.add(&key, &info)?;
+            }
+
+            Ok(())
+        })?;
+
+        debug!("Stored picture {hash} for {uploader}");
+
+        Ok(hash)
+    }
+
+    /// Checks that there aren't too many pictures and that `username` uploaded all of them. Someone else's picture is treated as not existing, so its hash can't be used to attach it to another request.
+    pub fn check_all(&self, hashes: &[String], username: &str) -> Result<(), Error> {
+        if hashes.len() > MAX_PICTURES {
+            return Err(Error::TooManyPictures);
+        }
+
+        for hash in hashes {
+            if self.pictures.get(&format!("{hash}/{username}"))?.is_none() {
+                return Err(Error::PictureDoesntExist);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The type and contents of a picture or its thumbnail
+    pub fn read(&self, hash: &str, thumbnail: bool) -> Result<Option<(String, Vec<u8>)>, Error> {
+        // Every uploader's record is of the same files
+        let picture = match self.pictures.scan_prefix(&format!("{hash}/")).next() {
+            Some(v) => v?.1,
+            None => return Ok(None),
+        };
+
+        let (file, mime_type) = if thumbnail {
+            (picture.thumbnail.as_str(), "image/jpeg")
+        } else {
+            (hash, picture.mime_type.as_str())
+        };
+
+        let data = fs::read(self.path(file)).map_err(Error::unexpected)?;
+
+        Ok(Some((mime_type.to_owned(), data)))
+    }
+
+    /// Files are spread over subdirectories by the first two characters of their hash so no one directory gets huge
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    fn write_file(&self, data: &[u8]) -> Result<String, Error> {
+        let hash = format!("{:x}", sha3::Sha3_256::digest(data));
+        let path = self.path(&hash);
+
+        if path.exists() {
+            return Ok(hash);
+        }
+
+        let parent = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(parent).map_err(Error::unexpected)?;
+
+        // Written to the side first so a crash can't leave a partial file under the real name
+        let temporary = parent.join(format!("{hash}.tmp"));
+        let mut file = File::create(&temporary).map_err(Error::unexpected)?;
+        file.write_all(data).map_err(Error::unexpected)?;
+        file.sync_all().map_err(Error::unexpected)?;
+        fs::rename(&temporary, &path).map_err(Error::unexpected)?;
+
+        Ok(hash)
+    }
+}
+
+/// Decodes base64 with either alphabet, with or without padding, since clients have sent all of them
+pub fn decode_base64(data: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+
+    STANDARD
+        .decode(data)
+        .or_else(|_| URL_SAFE.decode(data))
+        .or_else(|_| STANDARD_NO_PAD.decode(data))
+        .or_else(|_| URL_SAFE_NO_PAD.decode(data))
+}
+
+/// The hashes of a request's pictures, for its JSON
+pub fn hashes(pictures: &ArchivedVec<ArchivedString>) -> Vec<&str> {
+    pictures.iter().map(|hash| hash.as_str()).collect()
+}
+
+fn encode_jpeg(picture: &DynamicImage, quality: u8) -> Result<Vec<u8>, Error> {
+    let mut jpeg = Vec::new();
+
+    DynamicImage::ImageRgb8(picture.to_rgb8())
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, quality))
+        .map_err(Error::unexpected)?;
+
+    Ok(jpeg)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadPictureInfo {
+    mime_type: String,
+    /// Base64 or base64url
+    data: String,
+}
+
+pub fn pictures_filters(
+    user_db: &UserDB,
+    picture_store: &PictureStore,
+) -> impl Filter<Extract = (Result<Body, Error>,), Error = Rejection> + Clone {
+    // Base64 takes 4 bytes for every 3, and there's a bit more for the rest of the JSON
+    let max_body = (*MAX_PICTURE_BYTES as u64) / 3 * 4 + 64 * 1024;
+
+    warp::path!("api" / "upload-picture")
+        .and(content_length_limit(max_body))
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(picture_store.to_owned()))
+        .and_then(move |bytes, users_db: UserDB, store: PictureStore| {
+            blocking(move || {
+                let username = authorize(&bytes)?;
+
+                if users_db.get(&username)?.is_none() {
+                    return Err(Error::msg("Oofy token"));
+                }
+
+                debug!("{username} is uploading a picture");
+
+                let info = extract_json::<UploadPictureInfo>(&bytes)?;
+
+                let data = decode_base64(&info.data)
+                    .map_err(|_| Error::UnsupportedPicture("The picture isn't valid base64"))?;
+
+                let hash = store.store(&data, &info.mime_type, &username)?;
+
+                Ok(Body::from(serde_json::to_string(&json!({ "hash": hash }))?))
+            })
+        })
+}
+
+/// Serves pictures at `/pictures/<hash>` and their thumbnails at `/pictures/<hash>/thumbnail`. The hash can't be guessed, so anyone who has it can see the picture.
+pub fn picture_files(
+    picture_store: &PictureStore,
+) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    let picture = warp::path!("pictures" / String).map(|hash| (hash, false));
+    let thumbnail = warp::path!("pictures" / String / "thumbnail").map(|hash| (hash, true));
+
+    picture
+        .or(thumbnail)
+        .unify()
+        .and(clone(picture_store.to_owned()))
+        .and_then(
+            |(hash, thumbnail): (String, bool), store: PictureStore| async move {
+                let read = tokio::task::spawn_blocking(move || {
+                    // Anything else can't be a hash, and could escape the picture directory
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Ok(None);
+                    }
+
+                    store.read(&hash, thumbnail)
+                })
+                .await
+                .unwrap_or_else(|e| Err(Error::unexpected(e)));
+
+                match read {
+                    Ok(Some((mime_type, data))) => Ok(Response::builder()
+                        .header(header::CONTENT_TYPE, mime_type)
+                        // A hash always has the same contents
+                        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+                        .body(Body::from(data))
+                        .unwrap_or_else(|e| Error::from(e).into_response())),
+                    Ok(None) => Err(warp::reject::not_found()),
+                    Err(e) => Ok(e.into_response()),
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+    use crate::db::Storage;
+
+    /// A store in its own temporary directory, which is removed when it's dropped
+    struct TempStore(PictureStore);
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let dir = env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            let storage = Storage::Sled(sled::Config::new().temporary(true).open().unwrap());
+
+            TempStore(PictureStore {
+                dir: Arc::new(dir),
+                pictures: Db::open(&storage, "pictures"),
+            })
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&*self.0.dir);
+        }
+    }
+
+    fn png() -> Vec<u8> {
+        let mut png = Vec::new();
+
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 16, |x, y| {
+            image::Rgb([x as u8 * 8, y as u8 * 16, 128])
+        }))
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+
+        png
+    }
+
+    #[test]
+    fn everyone_who_uploads_a_picture_can_use_it() {
+        let store = TempStore::new("pictures-shared");
+        let data = png();
+
+        let hash = store.0.store(&data, "image/png", "alice").unwrap();
+        assert_eq!(store.0.store(&data, "image/png", "bob").unwrap(), hash);
+
+        store.0.check_all(&[hash.to_owned()], "alice").unwrap();
+        store.0.check_all(&[hash.to_owned()], "bob").unwrap();
+        assert!(matches!(
+            store.0.check_all(&[hash.to_owned()], "carol"),
+            Err(Error::PictureDoesntExist)
+        ));
+
+        let (mime_type, _) = store.0.read(&hash, false).unwrap().unwrap();
+        assert_eq!(mime_type, "image/png");
+        assert!(store.0.read(&hash, true).unwrap().is_some());
+    }
+
+    #[test]
+    fn decoding_every_kind_of_base64() {
+        // Encodes to `+/` in the standard alphabet and `-_` in the URL safe one, with padding
+        let data = [0xfb, 0xff, 0xbf, 0xfe];
+
+        for encoded in ["+/+//g==", "-_-__g==", "+/+//g", "-_-__g"] {
+            assert_eq!(decode_base64(encoded).unwrap(), data);
+        }
+
+        assert!(decode_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn pictures_have_to_be_what_they_say() {
+        let store = TempStore::new("pictures-checked");
+
+        assert!(matches!(
+            store.0.store(&png(), "image/jpeg", "alice"),
+            Err(Error::UnsupportedPicture(_))
+        ));
+        assert!(matches!(
+            store.0.store(b"not a picture", "image/png", "alice"),
+            Err(Error::UnsupportedPicture(_))
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+//! How volunteers' work lists are ordered. Every request gets a priority that goes down with distance and up with how long it's waited, how urgent the senior says it is, and how often the senior's requests have gone unhelped, so that nobody is stuck at the bottom of everyone's list just because they live far away.
+
+use std::env;
+
+use once_cell::sync::Lazy;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+
+/// Why a request is more urgent than usual
+#[derive(
+    Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum Urgency {
+    MedicalAppointment,
+    MobilityIssue,
+}
+
+/// How many urgency reasons there are, which bounds how much they can add
+const URGENCY_REASONS: u32 = 2;
+
+/// Waiting longer than this doesn't raise a request's priority any further
+const MAX_AGE_HOURS: f64 = 72.;
+
+/// Unhelped requests past this many don't raise a request's priority any further
+const MAX_UNHELPED: u32 = 5;
+
+/// How much each factor counts towards a request's priority. Each is set with an environment variable.
+struct Weights {
+    /// Taken away per kilometer, `PRIORITY_DISTANCE_WEIGHT`
+    distance: f64,
+    /// Added per hour the request has waited, `PRIORITY_AGE_WEIGHT`
+    age: f64,
+    /// Added per urgency reason, `PRIORITY_URGENCY_WEIGHT`
+    urgency: f64,
+    /// Added per earlier request of the senior's that expired, `PRIORITY_UNHELPED_WEIGHT`
+    unhelped: f64,
+}
+
+fn weight(name: &str, default: f64) -> f64 {
+    match env::var(name) {
+        Ok(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("`{name}` should be a number")),
+        Err(_) => default,
+    }
+}
+
+static WEIGHTS: Lazy<Weights> = Lazy::new(|| Weights {
+    distance: weight("PRIORITY_DISTANCE_WEIGHT", 1.),
+    age: weight("PRIORITY_AGE_WEIGHT", 0.25),
+    urgency: weight("PRIORITY_URGENCY_WEIGHT", 10.),
+    unhelped: weight("PRIORITY_UNHELPED_WEIGHT", 5.),
+});
+
+/// The priority of a request `dist` meters away that was made `age` milliseconds ago. Higher comes first.
+pub fn priority(dist: f64, age: i64, urgency: &[Urgency], unhelped: u32) -> f64 {
+    let weights = &*WEIGHTS;
+    let age_hours = (age.max(0) as f64 / (60. * 60. * 1000.)).min(MAX_AGE_HOURS);
+
+    -dist / 1000. * weights.distance
+        + age_hours * weights.age
+        + urgency.len().min(URGENCY_REASONS as usize) as f64 * weights.urgency
+        + unhelped.min(MAX_UNHELPED) as f64 * weights.unhelped
+}
+
+/// The highest priority any request `dist` meters away could have, used to stop looking once every request left must rank below what's been found.
+pub fn best_possible_priority(dist: f64) -> f64 {
+    let weights = &*WEIGHTS;
+
+    -dist / 1000. * weights.distance
+        + MAX_AGE_HOURS * weights.age.max(0.)
+        + URGENCY_REASONS as f64 * weights.urgency.max(0.)
+        + MAX_UNHELPED as f64 * weights.unhelped.max(0.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 60 * 60 * 1000;
+
+    #[test]
+    fn factors_move_priority_the_right_way() {
+        let base = priority(2000., HOUR, &[], 0);
+
+        assert!(priority(1000., HOUR, &[], 0) > base);
+        assert!(priority(2000., 10 * HOUR, &[], 0) > base);
+        assert!(priority(2000., HOUR, &[Urgency::MobilityIssue], 0) > base);
+        assert!(priority(2000., HOUR, &[], 2) > base);
+    }
+
+    #[test]
+    fn factors_are_capped() {
+        let max_age = (MAX_AGE_HOURS as i64) * HOUR;
+
+        assert_eq!(
+            priority(0., max_age, &[], 0),
+            priority(0., 10 * max_age, &[], 0)
+        );
+        assert_eq!(
+            priority(0., 0, &[], MAX_UNHELPED),
+            priority(0., 0, &[], MAX_UNHELPED + 10)
+        );
+
+        // Listing a reason twice doesn't count past the number of reasons there are
+        let urgency = [
+            Urgency::MedicalAppointment,
+            Urgency::MobilityIssue,
+            Urgency::MobilityIssue,
+        ];
+        assert_eq!(
+            priority(0., 0, &urgency, 0),
+            priority(0., 0, &urgency[..2], 0)
+        );
+
+        // Requests from the future don't rank below new ones
+        assert_eq!(priority(0., -HOUR, &[], 0), priority(0., 0, &[], 0));
+    }
+
+    #[test]
+    fn best_possible_priority_is_an_upper_bound() {
+        let urgency = [Urgency::MedicalAppointment, Urgency::MobilityIssue];
+
+        for dist in [0., 500., 25_000.] {
+            let best = best_possible_priority(dist);
+
+            assert!(priority(dist, 0, &[], 0) <= best);
+            assert!(priority(dist, 1000 * HOUR, &urgency, 100) <= best);
+        }
+
+        assert!(best_possible_priority(1000.) > best_possible_priority(2000.));
+    }
+}
@@ -0,0 +1,119 @@
+//! Volunteers only learn roughly where a senior is until they accept their request. Until then they're given the city and ZIP code instead of the address, the middle of a grid square instead of the exact location, and distances rounded up, and filtering by distance uses the rounded distance so it can't be used to narrow it down.
+
+use chrono::Utc;
+use log::info;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use ulid::Ulid;
+
+use crate::{
+    db::{Db, Transactional},
+    distance_meters,
+    errors::Error,
+    Location,
+};
+
+/// Distances are rounded up to a multiple of this many meters
+const DISTANCE_STEP: f64 = 500.;
+
+/// The size of the grid squares locations are moved to the middle of, in degrees. About a kilometer north to south.
+const GRID_DEGREES: f64 = 0.01;
+
+/// The farthest in meters a location can be from the middle of its grid square, which is half the diagonal of a square at the equator
+pub const MAX_APPROXIMATION_ERROR: f64 = 800.;
+
+/// The distance rounded up to the next step. Only a distance of exactly zero stays zero, so nobody is told a request is right next to them.
+pub fn approximate_distance(dist: f64) -> f64 {
+    (dist / DISTANCE_STEP).ceil() * DISTANCE_STEP
+}
+
+/// The middle of the grid square the location is in. Every location in the square gives the same answer, so asking again doesn't help.
+pub fn approximate_location(Location(lat, lon): Location) -> Location {
+    let snap = |degrees: f64| ((degrees / GRID_DEGREES).floor() + 0.5) * GRID_DEGREES;
+
+    Location(snap(lat), snap(lon))
+}
+
+/// How far a request at `location` is from `from` before the volunteer has accepted it. It's measured to the middle of the request's grid square, so volunteers in different places can't put their distances together to find the exact location.
+pub fn approximate_distance_to(from: Location, location: Location) -> f64 {
+    approximate_distance(distance_meters(from, approximate_location(location)))
+}
+
+/// A volunteer being shown a senior's exact address
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct AddressAccess {
+    volunteer: String,
+    senior: String,
+    request: String,
+    time: i64,
+}
+
+/// Keyed by a ULID so they're in the order they happened
+pub type AddressAccessDB = Db<100, AddressAccess>;
+
+/// Records that a volunteer was shown a senior's exact address, so who has seen it can be found later. They're also logged under the `address_access` target.
+pub fn log_address_access(
+    accesses: &AddressAccessDB,
+    volunteer: &str,
+    senior: &str,
+    request_id: &str,
+) -> Result<(), Error> {
+    let now = Utc::now().timestamp_millis();
+
+    let access = AddressAccess {
+        volunteer: volunteer.to_owned(),
+        senior: senior.to_owned(),
+        request: request_id.to_owned(),
+        time: now,
+    };
+
+    accesses.transaction(|accesses_db| {
+        accesses_db.add(
+            &Ulid::from_parts(now as u64, rand::random()).to_string(),
+            &access,
+        )?;
+
+        Ok(())
+    })?;
+
+    info!(
+        target: "address_access",
+        "`{volunteer}` was shown the address of `{senior}` for request {request_id}"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances_dont_depend_on_where_in_the_square_a_request_is() {
+        let volunteers = [
+            Location(44.98, -93.27),
+            Location(44.90, -93.20),
+            Location(45.10, -93.40),
+        ];
+
+        for volunteer in volunteers {
+            assert_eq!(
+                approximate_distance_to(volunteer, Location(44.9512, -93.2981)),
+                approximate_distance_to(volunteer, Location(44.9589, -93.2903))
+            );
+        }
+    }
+
+    #[test]
+    fn approximations_stay_close() {
+        let location = Location(44.9589, -93.2903);
+
+        assert!(
+            distance_meters(location, approximate_location(location)) <= MAX_APPROXIMATION_ERROR
+        );
+        assert_eq!(approximate_distance(0.), 0.);
+        assert_eq!(approximate_distance(1.), 500.);
+        assert_eq!(approximate_distance(500.), 500.);
+        assert_eq!(approximate_distance(501.), 1000.);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{env, time::Duration};
+
+use chrono::Utc;
+use log::{info, trace, warn};
+use once_cell::sync::Lazy;
+
+use crate::{
+    db::Transactional,
+    dispatch::Dispatcher,
+    errors::Error,
+    events::EventBus,
+    help_requests::{new_request_id, unhelped_count},
+    notifications::{Notification, Notifier},
+    spatial_index::RequestIndex,
+    webhooks::{WebhookEvent, Webhooks},
+    HelpRequest, HelpRequestDB, HelpRequestState, RecurringRequestDB, UserDB, UserType,
+    VolunteerProfileDB,
+};
+
+/// How long before its window the help request for a recurring request is made, set with `RECURRING_REQUEST_LEAD_HOURS`
+static LEAD_TIME: Lazy<i64> = Lazy::new(|| {
+    let hours: i64 = match env::var("RECURRING_REQUEST_LEAD_HOURS") {
+        Ok(v) => v
+            .parse()
+            .expect("`RECURRING_REQUEST_LEAD_HOURS` should be a non-negative integer"),
+        Err(_) => 24,
+    };
+
+    hours * 60 * 60 * 1000
+});
+
+/// How often recurring requests are checked for help requests that are due
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Periodically makes help requests from the recurring requests whose next window is coming up.
+#[allow(clippy::too_many_arguments)]
+pub async fn schedule_recurring_requests(
+    users: UserDB,
+    help_requests: HelpRequestDB,
+    recurring_requests: RecurringRequestDB,
+    volunteer_profiles: VolunteerProfileDB,
+    request_index: RequestIndex,
+    dispatcher: Dispatcher,
+    events: EventBus,
+    notifier: Notifier,
+    webhooks: Webhooks,
+) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let users = users.to_owned();
+        let help_requests = help_requests.to_owned();
+        let recurring_requests = recurring_requests.to_owned();
+        let volunteer_profiles = volunteer_profiles.to_owned();
+        let request_index = request_index.to_owned();
+        let dispatcher = dispatcher.to_owned();
+        let events = events.to_owned();
+        let notifier = notifier.to_owned();
+        let webhooks = webhooks.to_owned();
+
+        match tokio::task::spawn_blocking(move || {
+            make_due_requests(
+                &users,
+                &help_requests,
+                &recurring_requests,
+                &volunteer_profiles,
+                &request_index,
+                &dispatcher,
+                &events,
+                &notifier,
+                &webhooks,
+            )
+        })
+        .await
+        {
+            Ok(Ok(0)) => trace!("No recurring requests were due"),
+            Ok(Ok(made)) => info!("Made {made} help requests from recurring requests"),
+            Ok(Err(e)) => warn!("Failed to make help requests from recurring requests: {e:?}"),
+            Err(e) => warn!("Failed to make help requests from recurring requests: {e}"),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn make_due_requests(
+    users: &UserDB,
+    help_requests: &HelpRequestDB,
+    recurring_requests: &RecurringRequestDB,
+    volunteer_profiles: &VolunteerProfileDB,
+    request_index: &RequestIndex,
+    dispatcher: &Dispatcher,
+    events: &EventBus,
+    notifier: &Notifier,
+    webhooks: &Webhooks,
+) -> Result<usize, Error> {
+    let now = Utc::now().timestamp_millis();
+    let mut made = 0;
+
+    for maybe_recurring in recurring_requests.iter() {
+        let (id, recurring) = maybe_recurring?;
+
+        if recurring.next_window.start - *LEAD_TIME > now {
+            continue;
+        }
+
+        let new_request = (
+            users,
+            help_requests,
+            recurring_requests,
+            volunteer_profiles,
+            webhooks.deliveries(),
+        )
+            .transaction(
+                |(users_db, requests_db, recurring_db, profiles_db, deliveries_db)| {
+                    let mut recurring = match recurring_db.get(&id)? {
+                        Some(v) => v.to_original(),
+                        None => return Ok(None),
+                    };
+
+                    // Windows that passed while the server was down are skipped
+                    while recurring.next_window.end <= now {
+                        recurring.next_window =
+                            recurring.next_window.after_days(recurring.every_days);
+                    }
+
+                    let window = recurring.next_window;
+
+                    if window.start - *LEAD_TIME > now {
+                        recurring_db.add(&id, &recurring)?;
+                        return Ok(None);
+                    }
+
+                    recurring.next_window = window.after_days(recurring.every_days);
+
+                    recurring_db.add(&id, &recurring)?;
+
+                    let mut senior = users_db
+                        .get(&recurring.username)?
+                        .ok_or_else(|| {
+                            Error::msg(
+                                "The senior of a recurring request doesn't exist in the database",
+                            )
+                        })?
+                        .to_original();
+
+                    let requests = match &mut senior.user_type {
+                        UserType::Senior(requests) => requests,
+                        _ => {
+                            return Err(Error::msg(
+                                "The user of a recurring request isn't a senior",
+                            )
+                            .into())
+                        }
+                    };
+
+                    // Seniors only have one unfinished request at a time, so this window is skipped
+                    if let Some(latest) = requests.last() {
+                        if let Some(request) = requests_db.get(latest)? {
+                            if !request.state.is_finished() {
+                                trace!("Skipped a recurring request of `{}`", recurring.username);
+                                return Ok(None);
+                            }
+                        }
+                    }
+
+                    let unhelped_before = unhelped_count(requests, &requests_db)?;
+
+                    let request_id = new_request_id(now);
+
+                    let mut help_request = HelpRequest {
+                        pictures: recurring.pictures.to_owned(),
+                        notes: recurring.notes.to_owned(),
+                        creation_time: now,
+                        state: HelpRequestState::Pending,
+                        username: recurring.username.to_owned(),
+                        volunteers_needed: recurring.volunteers_needed,
+                        location: recurring.location,
+                        finished_time: None,
+                        window: Some(window),
+                        recurring_request: Some(id.to_owned()),
+                        task: recurring.task,
+                        effort: recurring.effort,
+                        equipment: recurring.equipment.to_owned(),
+                        urgency: recurring.urgency.to_owned(),
+                        unhelped_before,
+                        after_pictures: Vec::new(),
+                    };
+
+                    let mut volunteers = Vec::new();
+                    let mut names = Vec::new();
+
+                    for subscriber in &recurring.subscribers {
+                        // Subscribers past the number of volunteers needed leave it to whoever took it first
+                        if volunteers.len() >= recurring.volunteers_needed as usize {
+                            break;
+                        }
+
+                        let mut volunteer = match users_db.get(subscriber)? {
+                            Some(v) => v.to_original(),
+                            None => continue,
+                        };
+
+                        // Subscribers who can't make it this time leave the request to everyone else
+                        if let Some(profile) = profiles_db.get(subscriber)? {
+                            let dist = help_request.distance_meters(volunteer.location);
+
+                            if !profile.can_reach(dist, (window.start, window.end), now) {
+                                trace!("`{subscriber}` can't make a recurring request this time");
+                                continue;
+                            }
+                        }
+
+                        if let UserType::Volunteer(accepted) = &mut volunteer.user_type {
+                            accepted.push(request_id.to_owned());
+                            users_db.add(subscriber, &volunteer)?;
+                            volunteers.push(subscriber.to_owned());
+                            names.push(volunteer.name);
+                        }
+                    }
+
+                    if !volunteers.is_empty() {
+                        help_request.state = HelpRequestState::AcceptedBy(volunteers);
+                    }
+
+                    requests_db.add(&request_id, &help_request)?;
+
+                    requests.push(request_id.to_owned());
+
+                    users_db.add(&recurring.username, &senior)?;
+
+                    webhooks.request_event(
+                        &users_db,
+                        &requests_db,
+                        &deliveries_db,
+                        WebhookEvent::Created,
+                        &request_id,
+                    )?;
+
+                    if !names.is_empty() {
+                        webhooks.request_event(
+                            &users_db,
+                            &requests_db,
+                            &deliveries_db,
+                            WebhookEvent::Accepted,
+                            &request_id,
+                        )?;
+                    }
+
+                    Ok(Some((request_id, help_request, names)))
+                },
+            )?;
+
+        if let Some((request_id, help_request, names)) = new_request {
+            request_index.insert(&request_id, help_request.location);
+            made += 1;
+
+            // The senior and any subscribers who took it hear about it, and so do nearby volunteers if it still needs more
+            events.request_updated(&request_id, &help_request, &[]);
+
+            if help_request.state.volunteers().len() < help_request.volunteers_needed as usize {
+                events.new_request(&request_id, help_request.location);
+            }
+
+            // Subscribers taking it is the same as them accepting it
+            for name in &names {
+                let notification = Notification::accepted(&request_id, name);
+
+                if let Err(e) = notifier.notify(&help_request.username, notification) {
+                    warn!("Failed to tell a senior their recurring request was accepted: {e:?}");
+                }
+            }
+
+            if let Err(e) = dispatcher.dispatch(&request_id) {
+                warn!("Failed to offer a recurring help request to a volunteer: {e:?}");
+            }
+        }
+    }
+
+    Ok(made)
+}
@@ -0,0 +1,342 @@
+use rkyv::{
+    string::ArchivedString, vec::ArchivedVec, Archive, Deserialize as RkyvDeserialize,
+    Serialize as RkyvSerialize,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::errors::Error;
+
+// Changing this changes the layout of stored help requests, which needs a migration in `schema`
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
+pub enum HelpRequestState {
+    /// Nobody has accepted the request yet
+    Pending,
+    /// Accepted by these volunteers. The request is still open to more of them until as many as it needs have accepted.
+    AcceptedBy(Vec<String>),
+    MarkedCompletedBy(Vec<String>),
+    /// The senior confirmed that the volunteers did the work
+    CompletedBy(Vec<String>),
+    Cancelled,
+    Expired,
+}
+
+fn usernames(users: &ArchivedVec<ArchivedString>) -> Vec<&str> {
+    users.iter().map(|user| user.as_str()).collect()
+}
+
+impl ArchivedHelpRequestState {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ArchivedHelpRequestState::Pending => json!("Pending"),
+            ArchivedHelpRequestState::AcceptedBy(users) => {
+                json!({ "AcceptedBy": usernames(users) })
+            }
+            ArchivedHelpRequestState::MarkedCompletedBy(users) => {
+                json!({ "MarkedCompletedBy": usernames(users) })
+            }
+            ArchivedHelpRequestState::CompletedBy(users) => {
+                json!({ "CompletedBy": usernames(users) })
+            }
+            ArchivedHelpRequestState::Cancelled => json!("Cancelled"),
+            ArchivedHelpRequestState::Expired => json!("Expired"),
+        }
+    }
+
+    /// Whether another volunteer can accept the request.
+    pub fn is_open(&self, volunteers_needed: u32) -> bool {
+        match self {
+            ArchivedHelpRequestState::Pending => true,
+            ArchivedHelpRequestState::AcceptedBy(users) => users.len() < volunteers_needed as usize,
+            _ => false,
+        }
+    }
+
+    /// Whether nothing else can happen to the request.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            ArchivedHelpRequestState::CompletedBy(_)
+                | ArchivedHelpRequestState::Cancelled
+                | ArchivedHelpRequestState::Expired
+        )
+    }
+
+    /// Whether the volunteer has accepted the request and hasn't marked it completed yet.
+    pub fn is_accepted_by(&self, username: &str) -> bool {
+        matches!(self, ArchivedHelpRequestState::AcceptedBy(users) if users.iter().any(|user| user == username))
+    }
+
+    /// Whether the volunteer has accepted the request and it isn't finished yet, whether or not they've marked it completed.
+    pub fn is_helped_by(&self, username: &str) -> bool {
+        match self {
+            ArchivedHelpRequestState::AcceptedBy(users)
+            | ArchivedHelpRequestState::MarkedCompletedBy(users) => {
+                users.iter().any(|user| user == username)
+            }
+            _ => false,
+        }
+    }
+
+    /// How many volunteers have accepted the request.
+    pub fn volunteer_count(&self) -> usize {
+        match self {
+            ArchivedHelpRequestState::AcceptedBy(users)
+            | ArchivedHelpRequestState::MarkedCompletedBy(users)
+            | ArchivedHelpRequestState::CompletedBy(users) => users.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// Everything that can happen to a help request. The volunteer's username is given where it matters who's doing it.
+#[derive(Clone, Copy)]
+pub enum Transition<'a> {
+    Accept {
+        volunteer: &'a str,
+        volunteers_needed: u32,
+    },
+    /// The volunteer no longer wants to do the work
+    Withdraw(&'a str),
+    MarkCompleted(&'a str),
+    ConfirmCompletion,
+    /// The senior says the work marked as completed wasn't done, so it goes back to the volunteer
+    DisputeCompletion,
+    Cancel,
+    Expire,
+}
+
+impl Transition<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Transition::Accept { .. } => "accept",
+            Transition::Withdraw(_) => "withdraw from",
+            Transition::MarkCompleted(_) => "mark as completed",
+            Transition::ConfirmCompletion => "confirm the completion of",
+            Transition::DisputeCompletion => "dispute the completion of",
+            Transition::Cancel => "cancel",
+            Transition::Expire => "expire",
+        }
+    }
+}
+
+impl HelpRequestState {
+    fn name(&self) -> &'static str {
+        match self {
+            HelpRequestState::Pending => "pending",
+            HelpRequestState::AcceptedBy(_) => "accepted",
+            HelpRequestState::MarkedCompletedBy(_) => "marked as completed",
+            HelpRequestState::CompletedBy(_) => "completed",
+            HelpRequestState::Cancelled => "cancelled",
+            HelpRequestState::Expired => "expired",
+        }
+    }
+
+    /// Whether nothing else can happen to the request.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            HelpRequestState::CompletedBy(_)
+                | HelpRequestState::Cancelled
+                | HelpRequestState::Expired
+        )
+    }
+
+    /// The volunteers who've accepted the request, marked it as completed, or completed it
+    pub fn volunteers(&self) -> &[String] {
+        match self {
+            HelpRequestState::AcceptedBy(volunteers)
+            | HelpRequestState::MarkedCompletedBy(volunteers)
+            | HelpRequestState::CompletedBy(volunteers) => volunteers,
+            HelpRequestState::Pending | HelpRequestState::Cancelled | HelpRequestState::Expired => {
+                &[]
+            }
+        }
+    }
+
+    /// Returns the state the request is in after `transition`, or an error if the transition isn't allowed from this state.
+    pub fn transition(&self, transition: Transition) -> Result<HelpRequestState, Error> {
+        use HelpRequestState::*;
+        use Transition::*;
+
+        match (self, transition) {
+            (Pending, Accept { volunteer, .. }) => Ok(AcceptedBy(vec![volunteer.to_owned()])),
+            (
+                AcceptedBy(volunteers),
+                Accept {
+                    volunteer,
+                    volunteers_needed,
+                },
+            ) if volunteers.len() < volunteers_needed as usize
+                && !volunteers.iter().any(|v| v == volunteer) =>
+            {
+                let mut volunteers = volunteers.to_owned();
+                volunteers.push(volunteer.to_owned());
+
+                Ok(AcceptedBy(volunteers))
+            }
+            (AcceptedBy(volunteers), Withdraw(volunteer))
+                if volunteers.iter().any(|v| v == volunteer) =>
+            {
+                let remaining = volunteers
+                    .iter()
+                    .filter(|v| *v != volunteer)
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                if remaining.is_empty() {
+                    Ok(Pending)
+                } else {
+                    Ok(AcceptedBy(remaining))
+                }
+            }
+            (AcceptedBy(volunteers), MarkCompleted(volunteer))
+                if volunteers.iter().any(|v| v == volunteer) =>
+            {
+                Ok(MarkedCompletedBy(volunteers.to_owned()))
+            }
+            (AcceptedBy(_), Withdraw(_) | MarkCompleted(_)) => Err(Error::RequestNotAcceptedByUser),
+            (MarkedCompletedBy(volunteers), ConfirmCompletion) => {
+                Ok(CompletedBy(volunteers.to_owned()))
+            }
+            (MarkedCompletedBy(volunteers), DisputeCompletion) => {
+                Ok(AcceptedBy(volunteers.to_owned()))
+            }
+            (Pending | AcceptedBy(_), Cancel) => Ok(Cancelled),
+            (Pending, Expire) => Ok(Expired),
+            (state, transition) => Err(Error::IllegalTransition {
+                from: state.name(),
+                transition: transition.name(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted_by(volunteers: &[&str]) -> HelpRequestState {
+        HelpRequestState::AcceptedBy(volunteers.iter().map(|v| v.to_string()).collect())
+    }
+
+    fn accept(volunteer: &str, volunteers_needed: u32) -> Transition<'_> {
+        Transition::Accept {
+            volunteer,
+            volunteers_needed,
+        }
+    }
+
+    fn is_illegal(result: Result<HelpRequestState, Error>) -> bool {
+        matches!(result, Err(Error::IllegalTransition { .. }))
+    }
+
+    #[test]
+    fn accepting() {
+        let state = HelpRequestState::Pending
+            .transition(accept("a", 2))
+            .unwrap();
+        assert_eq!(state.volunteers(), ["a"]);
+
+        let state = state.transition(accept("b", 2)).unwrap();
+        assert_eq!(state.volunteers(), ["a", "b"]);
+
+        // Full, and nobody can accept twice
+        assert!(is_illegal(state.transition(accept("c", 2))));
+        assert!(is_illegal(accepted_by(&["a"]).transition(accept("a", 2))));
+    }
+
+    #[test]
+    fn withdrawing() {
+        let state = accepted_by(&["a", "b"])
+            .transition(Transition::Withdraw("a"))
+            .unwrap();
+        assert_eq!(state.volunteers(), ["b"]);
+
+        let state = state.transition(Transition::Withdraw("b")).unwrap();
+        assert!(matches!(state, HelpRequestState::Pending));
+
+        assert!(matches!(
+            accepted_by(&["a"]).transition(Transition::Withdraw("b")),
+            Err(Error::RequestNotAcceptedByUser)
+        ));
+        assert!(is_illegal(
+            HelpRequestState::Pending.transition(Transition::Withdraw("a"))
+        ));
+    }
+
+    #[test]
+    fn completing() {
+        let marked = accepted_by(&["a", "b"])
+            .transition(Transition::MarkCompleted("b"))
+            .unwrap();
+        assert!(matches!(marked, HelpRequestState::MarkedCompletedBy(_)));
+        assert_eq!(marked.volunteers(), ["a", "b"]);
+
+        let disputed = marked.transition(Transition::DisputeCompletion).unwrap();
+        assert!(matches!(disputed, HelpRequestState::AcceptedBy(_)));
+
+        let completed = marked.transition(Transition::ConfirmCompletion).unwrap();
+        assert!(matches!(completed, HelpRequestState::CompletedBy(_)));
+        assert!(completed.is_finished());
+
+        assert!(matches!(
+            accepted_by(&["a"]).transition(Transition::MarkCompleted("b")),
+            Err(Error::RequestNotAcceptedByUser)
+        ));
+        assert!(is_illegal(
+            accepted_by(&["a"]).transition(Transition::ConfirmCompletion)
+        ));
+        assert!(is_illegal(
+            HelpRequestState::Pending.transition(Transition::DisputeCompletion)
+        ));
+    }
+
+    #[test]
+    fn cancelling_and_expiring() {
+        assert!(matches!(
+            HelpRequestState::Pending.transition(Transition::Cancel),
+            Ok(HelpRequestState::Cancelled)
+        ));
+        assert!(matches!(
+            accepted_by(&["a"]).transition(Transition::Cancel),
+            Ok(HelpRequestState::Cancelled)
+        ));
+        assert!(matches!(
+            HelpRequestState::Pending.transition(Transition::Expire),
+            Ok(HelpRequestState::Expired)
+        ));
+
+        // Work that's been marked as completed has to be confirmed or disputed first
+        let marked = HelpRequestState::MarkedCompletedBy(vec!["a".into()]);
+        assert!(is_illegal(marked.transition(Transition::Cancel)));
+        assert!(is_illegal(
+            accepted_by(&["a"]).transition(Transition::Expire)
+        ));
+    }
+
+    #[test]
+    fn finished_requests_cant_change() {
+        let finished = [
+            HelpRequestState::CompletedBy(vec!["a".into()]),
+            HelpRequestState::Cancelled,
+            HelpRequestState::Expired,
+        ];
+
+        for state in finished {
+            assert!(state.is_finished());
+
+            for transition in [
+                accept("b", 2),
+                Transition::Withdraw("a"),
+                Transition::MarkCompleted("a"),
+                Transition::ConfirmCompletion,
+                Transition::DisputeCompletion,
+                Transition::Cancel,
+                Transition::Expire,
+            ] {
+                assert!(is_illegal(state.transition(transition)));
+            }
+        }
+    }
+}
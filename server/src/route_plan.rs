@@ -0,0 +1,145 @@
+//! Orders a volunteer's visits so they travel as little as possible. Finding the best order is the travelling salesman problem, so a greedy nearest neighbour route is improved with 2-opt until no reversal of part of it makes it any shorter.
+
+use crate::{distance_meters, Location};
+
+/// 2-opt stops after this many passes even if it could still improve the route
+const MAX_PASSES: usize = 50;
+
+/// The order to visit `stops` in, starting from `start` and not returning, as indices into `stops`
+pub fn plan_route(start: Location, stops: &[Location]) -> Vec<usize> {
+    // Index 0 is the start and the stops follow
+    let points = std::iter::once(start)
+        .chain(stops.iter().copied())
+        .collect::<Vec<_>>();
+    let dist = points
+        .iter()
+        .map(|&a| {
+            points
+                .iter()
+                .map(|&b| distance_meters(a, b))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut route = nearest_neighbour(&dist);
+
+    two_opt(&dist, &mut route);
+
+    route.into_iter().map(|point| point - 1).collect()
+}
+
+/// Repeatedly visits the closest point that hasn't been visited yet
+fn nearest_neighbour(dist: &[Vec<f64>]) -> Vec<usize> {
+    let mut unvisited = (1..dist.len()).collect::<Vec<_>>();
+    let mut route = Vec::with_capacity(unvisited.len());
+    let mut at = 0;
+
+    while !unvisited.is_empty() {
+        let closest = (0..unvisited.len())
+            .min_by(|&a, &b| dist[at][unvisited[a]].total_cmp(&dist[at][unvisited[b]]))
+            .unwrap_or(0);
+
+        at = unvisited.swap_remove(closest);
+        route.push(at);
+    }
+
+    route
+}
+
+/// Reverses parts of the route while doing so makes it shorter. The route doesn't return to the start, so the last stop has nothing after it.
+fn two_opt(dist: &[Vec<f64>], route: &mut [usize]) {
+    let n = route.len();
+
+    for _ in 0..MAX_PASSES {
+        let mut improved = false;
+
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let before = if i == 0 { 0 } else { route[i - 1] };
+                let (first, last) = (route[i], route[j]);
+
+                let removed =
+                    dist[before][first] + route.get(j + 1).map_or(0., |&after| dist[last][after]);
+                let added =
+                    dist[before][last] + route.get(j + 1).map_or(0., |&after| dist[first][after]);
+
+                // Ignore differences too small to matter so rounding can't make it loop
+                if added < removed - 1e-6 {
+                    route[i..=j].reverse();
+                    improved = true;
+                }
+            }
+        }
+
+        if !improved {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Distances between points on a line at `positions`
+    fn line(positions: &[f64]) -> Vec<Vec<f64>> {
+        positions
+            .iter()
+            .map(|a| positions.iter().map(|b| (a - b).abs()).collect())
+            .collect()
+    }
+
+    fn length(dist: &[Vec<f64>], route: &[usize]) -> f64 {
+        std::iter::once(0)
+            .chain(route.iter().copied())
+            .zip(route.iter().copied())
+            .map(|(a, b)| dist[a][b])
+            .sum()
+    }
+
+    #[test]
+    fn two_opt_undoes_greedy_mistakes() {
+        // Going to the closest stop first means doubling back past the start
+        let dist = line(&[0., 1., -1.5, 4.]);
+
+        let mut route = nearest_neighbour(&dist);
+        assert_eq!(route, [1, 2, 3]);
+        assert_eq!(length(&dist, &route), 9.);
+
+        two_opt(&dist, &mut route);
+        assert_eq!(route, [2, 1, 3]);
+        assert_eq!(length(&dist, &route), 7.);
+    }
+
+    #[test]
+    fn stops_along_a_road_are_visited_in_order() {
+        let start = Location(44.95, -93.3);
+        let stops = [
+            Location(44.95, -93.1),
+            Location(44.95, -93.25),
+            Location(44.95, -93.0),
+            Location(44.95, -93.2),
+        ];
+
+        assert_eq!(plan_route(start, &stops), [1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn every_stop_is_visited_once() {
+        let start = Location(44.97, -93.26);
+        let stops = (0..12)
+            .map(|i| Location(44.9 + (i * 7 % 12) as f64 * 0.01, -93.3 + i as f64 * 0.01))
+            .collect::<Vec<_>>();
+
+        let mut route = plan_route(start, &stops);
+        route.sort_unstable();
+
+        assert_eq!(route, (0..stops.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn no_stops() {
+        assert!(plan_route(Location(0., 0.), &[]).is_empty());
+        assert_eq!(plan_route(Location(0., 0.), &[Location(1., 1.)]), [0]);
+    }
+}
@@ -0,0 +1,280 @@
+//! Older layouts of the types in storage. rkyv reads values in place, so whenever a stored type changes its previous layout is kept here and every value is rewritten at startup.
+
+use log::{info, warn};
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::Archive;
+
+use crate::{
+    address::Address,
+    db::{Db, Transactional},
+    errors::Error,
+    pictures::{decode_base64, PictureStore},
+    request_state,
+    tasks::{Effort, TaskType},
+    HelpRequest, HelpRequestDB, InfallibleDeserialize, User, UserDB, UserType,
+};
+
+/// The layout version of the values in each database, keyed by the database's name. Databases without an entry are at version 0.
+pub type SchemaVersionDB = Db<8, u32>;
+
+/// Users as they were before the layouts were versioned.
+mod users_v0 {
+    use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+
+    use crate::Location;
+
+    #[derive(Archive, RkyvSerialize, RkyvDeserialize)]
+    pub enum UserType {
+        Volunteer(Vec<String>),
+        Senior(Option<String>),
+    }
+
+    #[derive(Archive, RkyvSerialize, RkyvDeserialize)]
+    #[repr(C)]
+    pub struct User {
+        pub username: String,
+        pub name: String,
+        pub address: String,
+        pub location: Location,
+        pub user_type: UserType,
+        pub salt: [u8; 32],
+        pub password_hash: Vec<u8>,
+    }
+}
+
+/// Help requests as they were before the layouts were versioned.
+mod help_requests_v0 {
+    use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+
+    #[derive(Archive, RkyvSerialize, RkyvDeserialize)]
+    pub enum HelpRequestState {
+        Pending,
+        AcceptedBy(String),
+        MarkedCompletedBy(String),
+    }
+
+    #[derive(Archive, RkyvSerialize, RkyvDeserialize)]
+    #[repr(C)]
+    pub struct HelpRequest {
+        pub picture: String,
+        pub notes: String,
+        pub creation_time: i64,
+        pub state: HelpRequestState,
+        pub username: String,
+    }
+}
+
+/// Brings every database up to the latest layout.
+pub fn migrate(
+    versions: &SchemaVersionDB,
+    users: &UserDB,
+    help_requests: &HelpRequestDB,
+    pictures: &PictureStore,
+) -> Result<(), Error> {
+    // Users go first, since migrating help requests reads them with the latest layout
+    migrate_db::<250, users_v0::User, _, _>(versions, users, 1, |old| {
+        use users_v0::ArchivedUserType as Old;
+
+        Ok(User {
+            username: old.username.to_string(),
+            name: old.name.to_string(),
+            address: split_old_address(&old.address),
+            location: old.location,
+            user_type: match &old.user_type {
+                Old::Volunteer(accepted) => UserType::Volunteer(accepted.deserialize()),
+                Old::Senior(request) => {
+                    UserType::Senior(request.iter().map(|id| id.to_string()).collect())
+                }
+            },
+            salt: old.salt,
+            password_hash: old.password_hash.to_vec(),
+        })
+    })?;
+
+    migrate_db::<150, help_requests_v0::HelpRequest, _, _>(versions, help_requests, 1, |old| {
+        use help_requests_v0::ArchivedHelpRequestState as Old;
+        use request_state::HelpRequestState as New;
+
+        let senior = users.get(&old.username)?.ok_or_else(|| {
+            Error::msg("The username in the help request doesn't exist in the database")
+        })?;
+
+        Ok(HelpRequest {
+            pictures: store_old_picture(pictures, &old.picture, &old.username)
+                .into_iter()
+                .collect(),
+            notes: old.notes.to_string(),
+            creation_time: old.creation_time,
+            state: match &old.state {
+                Old::Pending => New::Pending,
+                Old::AcceptedBy(user) => New::AcceptedBy(vec![user.to_string()]),
+                Old::MarkedCompletedBy(user) => New::MarkedCompletedBy(vec![user.to_string()]),
+            },
+            username: old.username.to_string(),
+            volunteers_needed: 1,
+            // Requests were made from where the senior lives
+            location: senior.location,
+            finished_time: None,
+            window: None,
+            recurring_request: None,
+            // Every request used to be for shoveling a driveway
+            task: TaskType::Driveway,
+            effort: Effort::Medium,
+            equipment: Vec::new(),
+            urgency: Vec::new(),
+            unhelped_before: 0,
+            after_pictures: Vec::new(),
+        })
+    })
+}
+
+/// Splits an address the way the app used to write them, with the street address, second line, `<city> <state> <ZIP code>` and country on separate lines. Anything else is kept whole as the first line.
+fn split_old_address(address: &str) -> Address {
+    let mut lines: Vec<&str> = address.lines().map(str::trim).collect();
+
+    if lines.last() == Some(&"United States of America") {
+        lines.pop();
+    }
+
+    let split = match lines.as_slice() {
+        [line1, middle @ .., last] => {
+            let mut parts = last.rsplitn(3, ' ');
+
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(zip), Some(state), Some(city)) => Address::new(
+                    line1.to_string(),
+                    middle.join(", "),
+                    city.to_owned(),
+                    state.to_owned(),
+                    zip.to_owned(),
+                )
+                .validate()
+                .ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    split.unwrap_or_else(|| {
+        let whole = lines
+            .iter()
+            .filter(|line| !line.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Address::new(
+            whole,
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+        )
+    })
+}
+
+/// Moves a picture that was stored inline into the picture store. Pictures used to be whatever the app sent, so ones that aren't base64 images, optionally as a data URL, are dropped.
+fn store_old_picture(pictures: &PictureStore, picture: &str, username: &str) -> Option<String> {
+    if picture.is_empty() {
+        return None;
+    }
+
+    let encoded = match picture.strip_prefix("data:") {
+        Some(url) => url.split_once(',').map_or(url, |(_, data)| data),
+        None => picture,
+    };
+
+    let stored = decode_base64(encoded)
+        .map_err(Error::unexpected)
+        .and_then(|data| {
+            let mime_type = image::guess_format(&data)
+                .map_err(Error::unexpected)?
+                .to_mime_type();
+
+            pictures.store(&data, mime_type, username)
+        });
+
+    match stored {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            warn!("Dropped a picture of `{username}` that couldn't be stored: {e:?}");
+            None
+        }
+    }
+}
+
+/// Rewrites every value in `db` from the `Old` layout if the database is at `version - 1`, all in one transaction so a database is never left with a mix of layouts.
+fn migrate_db<const M: usize, Old, const N: usize, T>(
+    versions: &SchemaVersionDB,
+    db: &Db<N, T>,
+    version: u32,
+    convert: impl Fn(&Old::Archived) -> Result<T, Error>,
+) -> Result<(), Error>
+where
+    Old: rkyv::Serialize<AllocSerializer<M>> + Archive,
+    Old::Archived: 'static,
+    T: rkyv::Serialize<AllocSerializer<N>> + Archive,
+    T::Archived: 'static,
+{
+    let name = db.name()?;
+
+    let current = versions.get(&name)?.map_or(0, |v| *v);
+
+    if current >= version {
+        return Ok(());
+    }
+
+    let converted = db
+        .retype::<M, Old>()
+        .iter()
+        .map(|maybe_v| {
+            let (key, old) = maybe_v?;
+
+            Ok((key, convert(&old)?))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    (versions, db).transaction(|(versions_db, db)| {
+        for (key, value) in &converted {
+            db.add(key, value)?;
+        }
+
+        versions_db.add(&name, &version)?;
+
+        Ok(())
+    })?;
+
+    info!(
+        "Migrated {} entries in the `{name}` database to version {version}",
+        converted.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitting_old_addresses() {
+        let split = split_old_address(
+            "511 Kenwood Pkwy\nApt 2\nMinneapolis MN 55403\nUnited States of America",
+        );
+        assert_eq!(
+            split.to_string(),
+            "511 Kenwood Pkwy, Apt 2, Minneapolis, MN 55403"
+        );
+
+        // The app wrote an empty second line when there wasn't one
+        let split =
+            split_old_address("123 Main St\n\nSaint Paul MN 55101\nUnited States of America");
+        assert_eq!(split.to_string(), "123 Main St, Saint Paul, MN 55101");
+
+        // Anything that doesn't split into a valid address is kept as the first line
+        let kept = split_old_address("somewhere\nnear the lake");
+        assert!(kept.to_string().starts_with("somewhere, near the lake, "));
+        assert!(kept.validate().is_err());
+    }
+}
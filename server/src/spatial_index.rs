@@ -0,0 +1,169 @@
+use std::sync::{Arc, RwLock};
+
+use log::info;
+use rkyv::{ser::serializers::AllocSerializer, Archive};
+use rstar::{primitives::GeomWithData, RTree};
+
+use crate::{db::Db, errors::Error, ArchivedUserType, HelpRequestDB, Location, UserDB};
+
+/// The mean radius of the earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// How much farther than the index's approximate distance a location might actually be
+pub const DISTANCE_TOLERANCE: f64 = 1.01;
+
+/// How many entries are read from the database at a time while building the index
+const SCAN_BATCH_SIZE: usize = 256;
+
+/// Locations are indexed as points on the unit sphere, where straight line distance increases with distance along the surface, so the R-tree's nearest neighbours are the nearest locations.
+type IndexedPoint = GeomWithData<[f64; 3], String>;
+
+fn to_unit_sphere(location: Location) -> [f64; 3] {
+    let (lat, long) = <(f64, f64)>::from(location);
+    let (lat, long) = (lat.to_radians(), long.to_radians());
+
+    [lat.cos() * long.cos(), lat.cos() * long.sin(), lat.sin()]
+}
+
+/// Converts a squared straight line distance between points on the unit sphere to meters along the surface of a spherical earth.
+fn chord_to_meters(chord_squared: f64) -> f64 {
+    2. * (chord_squared.sqrt() / 2.).min(1.).asin() * EARTH_RADIUS
+}
+
+/// Reads every entry of `db` a batch at a time, keeping the locations `f` returns under their key
+fn load<const N: usize, T: rkyv::Serialize<AllocSerializer<N>> + Archive>(
+    db: &Db<N, T>,
+    f: impl Fn(&T::Archived) -> Option<Location>,
+) -> Result<Vec<IndexedPoint>, Error>
+where
+    T::Archived: 'static,
+{
+    let mut points = Vec::new();
+    let mut last_key = String::new();
+
+    loop {
+        let batch = db
+            .scan_from(&last_key, SCAN_BATCH_SIZE)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let batch_len = batch.len();
+
+        for (key, value) in batch {
+            if let Some(location) = f(&value) {
+                points.push(IndexedPoint::new(to_unit_sphere(location), key.to_owned()));
+            }
+
+            last_key = key;
+        }
+
+        if batch_len < SCAN_BATCH_SIZE {
+            break;
+        }
+    }
+
+    Ok(points)
+}
+
+/// Keys indexed by location, shared by the request and volunteer indexes
+#[derive(Clone)]
+struct PointIndex(Arc<RwLock<RTree<IndexedPoint>>>);
+
+impl PointIndex {
+    fn new(points: Vec<IndexedPoint>) -> PointIndex {
+        PointIndex(Arc::new(RwLock::new(RTree::bulk_load(points))))
+    }
+
+    fn insert(&self, key: &str, location: Location) {
+        self.0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(IndexedPoint::new(to_unit_sphere(location), key.to_owned()));
+    }
+
+    fn remove(&self, key: &str, location: Location) {
+        self.0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&IndexedPoint::new(to_unit_sphere(location), key.to_owned()));
+    }
+
+    fn with_nearest<R>(
+        &self,
+        location: Location,
+        f: impl FnOnce(&mut dyn Iterator<Item = (&str, f64)>) -> R,
+    ) -> R {
+        let tree = self.0.read().unwrap_or_else(|e| e.into_inner());
+
+        let mut nearest = tree
+            .nearest_neighbor_iter_with_distance_2(&to_unit_sphere(location))
+            .map(|(point, chord_squared)| (point.data.as_str(), chord_to_meters(chord_squared)));
+
+        f(&mut nearest)
+    }
+}
+
+/// An in-memory index of where every unfinished help request is. It's rebuilt from the database at startup and has to be kept up to date whenever a request is added or finished.
+#[derive(Clone)]
+pub struct RequestIndex(PointIndex);
+
+impl RequestIndex {
+    pub fn build(help_requests: &HelpRequestDB) -> Result<RequestIndex, Error> {
+        let requests = load(help_requests, |request| {
+            (!request.state.is_finished()).then_some(request.location)
+        })?;
+
+        info!("Indexed the locations of {} help requests", requests.len());
+
+        Ok(RequestIndex(PointIndex::new(requests)))
+    }
+
+    pub fn insert(&self, id: &str, location: Location) {
+        self.0.insert(id, location)
+    }
+
+    pub fn remove(&self, id: &str, location: Location) {
+        self.0.remove(id, location)
+    }
+
+    /// Calls `f` with the IDs of the indexed requests from nearest to farthest from `location`, along with their approximate distance in meters. The distance assumes a spherical earth, so it can be off from the geodesic distance by about half a percent.
+    pub fn with_nearest<R>(
+        &self,
+        location: Location,
+        f: impl FnOnce(&mut dyn Iterator<Item = (&str, f64)>) -> R,
+    ) -> R {
+        self.0.with_nearest(location, f)
+    }
+}
+
+/// An in-memory index of where every volunteer lives, used to find who to offer requests to. It's rebuilt from the database at startup and has to be kept up to date whenever a volunteer signs up or moves.
+#[derive(Clone)]
+pub struct VolunteerIndex(PointIndex);
+
+impl VolunteerIndex {
+    pub fn build(users: &UserDB) -> Result<VolunteerIndex, Error> {
+        let volunteers = load(users, |user| {
+            matches!(user.user_type, ArchivedUserType::Volunteer(_)).then_some(user.location)
+        })?;
+
+        info!("Indexed the locations of {} volunteers", volunteers.len());
+
+        Ok(VolunteerIndex(PointIndex::new(volunteers)))
+    }
+
+    pub fn insert(&self, username: &str, location: Location) {
+        self.0.insert(username, location)
+    }
+
+    pub fn remove(&self, username: &str, location: Location) {
+        self.0.remove(username, location)
+    }
+
+    /// Calls `f` with the usernames of volunteers from nearest to farthest from `location`, with the same approximate distances as `RequestIndex::with_nearest`.
+    pub fn with_nearest<R>(
+        &self,
+        location: Location,
+        f: impl FnOnce(&mut dyn Iterator<Item = (&str, f64)>) -> R,
+    ) -> R {
+        self.0.with_nearest(location, f)
+    }
+}
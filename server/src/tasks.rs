@@ -0,0 +1,63 @@
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+
+/// What the senior needs done
+#[derive(
+    Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum TaskType {
+    Driveway,
+    Sidewalk,
+    RoofRaking,
+    SaltAndSand,
+    Groceries,
+}
+
+/// Roughly how much work a request is
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Archive,
+    RkyvSerialize,
+    RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum Effort {
+    Small,
+    Medium,
+    Large,
+}
+
+impl Effort {
+    /// Roughly how long a volunteer takes to do a request this size, in milliseconds
+    pub fn estimated_time(self) -> i64 {
+        let minutes = match self {
+            Effort::Small => 30,
+            Effort::Medium => 60,
+            Effort::Large => 120,
+        };
+
+        minutes * 60 * 1000
+    }
+}
+
+#[derive(
+    Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum Equipment {
+    Shovel,
+    Snowblower,
+    RoofRake,
+    Car,
+}
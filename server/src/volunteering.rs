@@ -1,5 +1,13 @@
-use log::{debug, error, trace};
-use serde::Deserialize;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashSet},
+    env,
+};
+
+use chrono::Utc;
+use log::{debug, error, trace, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use warp::{
@@ -10,14 +18,41 @@ use warp::{
 
 use crate::{
     authorization::authorize,
-    clone, clone_dbs,
+    availability::TravelMode,
+    blocking, clone, clone_dbs,
     db::{Archived, Transactional},
+    dispatch::Dispatcher,
     distance_meters,
     errors::Error,
-    extract_json, ArchivedUserType, HelpRequestDB, HelpRequestState, InfallibleDeserialize, User,
-    UserDB, UserType,
+    events::EventBus,
+    extract_json,
+    notifications::{Notification, Notifier},
+    pictures::{hashes, PictureStore},
+    priority::{best_possible_priority, priority, Urgency},
+    privacy::{
+        approximate_distance_to, approximate_location, log_address_access, AddressAccessDB,
+        MAX_APPROXIMATION_ERROR,
+    },
+    request_state::Transition,
+    route_plan::plan_route,
+    spatial_index::{RequestIndex, DISTANCE_TOLERANCE},
+    tasks::{Effort, Equipment, TaskType},
+    webhooks::{WebhookEvent, Webhooks},
+    ArchivedUserType, HelpRequestDB, Location, Page, RecurringRequestDB, TimeWindow, User, UserDB,
+    UserType, VolunteerProfile, VolunteerProfileDB,
 };
 
+/// Whether volunteers have to attach a picture of the finished work when marking a request completed, set with `REQUIRE_AFTER_PICTURE`
+static REQUIRE_AFTER_PICTURE: Lazy<bool> = Lazy::new(|| match env::var("REQUIRE_AFTER_PICTURE") {
+    Ok(v) => v
+        .parse()
+        .expect("`REQUIRE_AFTER_PICTURE` should be `true` or `false`"),
+    Err(_) => false,
+});
+
+/// How many characters of a request's notes are shown when looking for work
+const NOTES_PREVIEW_LENGTH: usize = 100;
+
 fn volunteering_endpoint(
     bytes: &Bytes,
     user_db: &UserDB,
@@ -38,61 +73,245 @@ fn volunteering_endpoint(
     callback(bytes, username, user)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn volunteering_filters(
     user_db: &UserDB,
     help_requests: &HelpRequestDB,
+    recurring_requests: &RecurringRequestDB,
+    volunteer_profiles: &VolunteerProfileDB,
+    address_accesses: &AddressAccessDB,
+    request_index: &RequestIndex,
+    dispatcher: &Dispatcher,
+    picture_store: &PictureStore,
+    events: &EventBus,
+    notifier: &Notifier,
+    webhooks: &Webhooks,
 ) -> impl Filter<Extract = (Result<Body, Error>,), Error = Rejection> + Clone {
     let request_work = warp::path!("api" / "request-work")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| {
-            volunteering_endpoint(&bytes, &users_db, |_, username, user| {
-                debug!("{username} is requesting work");
-                request_work(user, &requests_db, &users_db)
+        .and(clone(volunteer_profiles.to_owned()))
+        .and(clone(request_index.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, profiles_db, index| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, user| {
+                    debug!("{username} is requesting work");
+                    request_work(
+                        user,
+                        extract_json(bytes)?,
+                        extract_json(bytes)?,
+                        &requests_db,
+                        &profiles_db,
+                        &index,
+                    )
+                })
             })
         });
 
     let get_request = warp::path!("api" / "get-request")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| {
-            volunteering_endpoint(&bytes, &users_db, |bytes, username, user| {
-                debug!("{username} is getting a request");
-                get_request(
-                    extract_json::<GetRequestData>(bytes)?.id,
-                    user,
-                    &users_db,
-                    &requests_db,
-                )
+        .and(clone(address_accesses.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, accesses_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, user| {
+                    debug!("{username} is getting a request");
+                    get_request(
+                        extract_json::<GetRequestData>(bytes)?.id,
+                        user,
+                        &users_db,
+                        &requests_db,
+                        &accesses_db,
+                    )
+                })
             })
         });
 
     let accept_request = warp::path!("api" / "accept-request")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| accept_request(&bytes, &users_db, &requests_db));
+        .and(clone(dispatcher.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and(clone(webhooks.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, dispatcher, events, notifier, webhooks| {
+                blocking(move || {
+                    accept_request(
+                        &bytes,
+                        &users_db,
+                        &requests_db,
+                        &dispatcher,
+                        &events,
+                        &notifier,
+                        &webhooks,
+                    )
+                })
+            },
+        );
+
+    let withdraw_request = warp::path!("api" / "withdraw-from-request")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(dispatcher.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, dispatcher, events, notifier| {
+                blocking(move || {
+                    withdraw_from_request(
+                        &bytes,
+                        &users_db,
+                        &requests_db,
+                        &dispatcher,
+                        &events,
+                        &notifier,
+                    )
+                })
+            },
+        );
+
+    let route_plan = warp::path!("api" / "route-plan")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(volunteer_profiles.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, profiles_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, user| {
+                    debug!("{username} is planning a route");
+                    route_plan(
+                        username,
+                        user,
+                        extract_json(bytes)?,
+                        &requests_db,
+                        &profiles_db,
+                    )
+                })
+            })
+        });
+
+    let offers = warp::path!("api" / "dispatch-offers")
+        .and(bytes())
+        .and(clone_dbs(user_db, help_requests))
+        .and(clone(dispatcher.to_owned()))
+        .and_then(move |bytes, users_db, requests_db, dispatcher| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |_, username, user| {
+                    debug!("{username} is getting the requests offered to them");
+                    dispatch_offers(username, user, &requests_db, &dispatcher)
+                })
+            })
+        });
+
+    let decline_offer = warp::path!("api" / "decline-offer")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(dispatcher.to_owned()))
+        .and_then(move |bytes, users_db, dispatcher: Dispatcher| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, _| {
+                    debug!("{username} is declining an offered request");
+                    dispatcher
+                        .decline(&extract_json::<GetRequestData>(bytes)?.id, &username)
+                        .map(|_| Body::from("{}"))
+                })
+            })
+        });
 
     let accepted_requests = warp::path!("api" / "accepted-requests")
         .and(bytes())
         .and(clone(user_db.to_owned()))
-        .map(move |bytes, users_db| {
-            volunteering_endpoint(&bytes, &users_db, |_, username, user| {
-                debug!("{username} is getting their accepted requests");
-                accepted_requests(user)
+        .and_then(move |bytes, users_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, user| {
+                    debug!("{username} is getting their accepted requests");
+                    accepted_requests(user, extract_json(bytes)?)
+                })
             })
         });
 
     let marking_completed = warp::path!("api" / "mark-request-completed")
         .and(bytes())
         .and(clone_dbs(user_db, help_requests))
-        .map(move |bytes, users_db, requests_db| {
-            volunteering_endpoint(&bytes, &users_db, |bytes, username, _| {
-                debug!("{username} is marking a request as completed");
-                marking_as_completed(
-                    username,
-                    extract_json::<GetRequestData>(bytes)?.id,
-                    &requests_db,
-                )
+        .and(clone(picture_store.to_owned()))
+        .and(clone(events.to_owned()))
+        .and(clone(notifier.to_owned()))
+        .and_then(
+            move |bytes, users_db, requests_db, pictures, events, notifier| {
+                blocking(move || {
+                    volunteering_endpoint(&bytes, &users_db, |bytes, username, user| {
+                        debug!("{username} is marking a request as completed");
+                        marking_as_completed(
+                            username,
+                            &user.name,
+                            extract_json(bytes)?,
+                            &requests_db,
+                            &pictures,
+                            &events,
+                            &notifier,
+                        )
+                    })
+                })
+            },
+        );
+
+    let subscribe = warp::path!("api" / "subscribe-to-recurring-request")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(recurring_requests.to_owned()))
+        .and_then(move |bytes, users_db, recurring_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, _| {
+                    debug!("{username} is subscribing to a recurring request");
+                    subscribe_to_recurring_request(
+                        username,
+                        extract_json::<GetRequestData>(bytes)?.id,
+                        &recurring_db,
+                    )
+                })
+            })
+        });
+
+    let unsubscribe = warp::path!("api" / "unsubscribe-from-recurring-request")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(recurring_requests.to_owned()))
+        .and_then(move |bytes, users_db, recurring_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, _| {
+                    debug!("{username} is unsubscribing from a recurring request");
+                    unsubscribe_from_recurring_request(
+                        username,
+                        extract_json::<GetRequestData>(bytes)?.id,
+                        &recurring_db,
+                    )
+                })
+            })
+        });
+
+    let get_profile = warp::path!("api" / "volunteer-profile")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(volunteer_profiles.to_owned()))
+        .and_then(move |bytes, users_db, profiles_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |_, username, _| {
+                    debug!("{username} is getting their volunteer profile");
+                    get_volunteer_profile(username, &profiles_db)
+                })
+            })
+        });
+
+    let set_profile = warp::path!("api" / "set-volunteer-profile")
+        .and(bytes())
+        .and(clone(user_db.to_owned()))
+        .and(clone(volunteer_profiles.to_owned()))
+        .and_then(move |bytes, users_db, profiles_db| {
+            blocking(move || {
+                volunteering_endpoint(&bytes, &users_db, |bytes, username, _| {
+                    debug!("{username} is setting their volunteer profile");
+                    set_volunteer_profile(username, extract_json(bytes)?, &profiles_db)
+                })
             })
         });
 
@@ -104,39 +323,204 @@ pub fn volunteering_filters(
             .unify()
             .or(accept_request)
             .unify()
+            .or(withdraw_request)
+            .unify()
             .or(marking_completed)
+            .unify()
+            .or(subscribe)
+            .unify()
+            .or(unsubscribe)
+            .unify()
+            .or(get_profile)
+            .unify()
+            .or(set_profile)
+            .unify()
+            .or(route_plan)
+            .unify()
+            .or(offers)
+            .unify()
+            .or(decline_offer)
             .unify(),
     )
 }
 
+/// What a volunteer looking for work sees about a request. They're ordered from highest priority to lowest and then by ID so that every request has a distinct position to page from.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkSummary {
+    id: String,
+    priority: f64,
+    /// When `priority` was worked out. Priorities grow as requests wait, so later pages are ranked at the same time.
+    ranked_at: i64,
+    dist: f64,
+    creation_time: i64,
+    notes_preview: String,
+    volunteers_needed: u32,
+    volunteers_accepted: usize,
+    window: Option<TimeWindow>,
+    task: TaskType,
+    effort: Effort,
+    equipment: Vec<Equipment>,
+    urgency: Vec<Urgency>,
+}
+
+impl WorkSummary {
+    fn is_after(&self, (_, priority, id): &WorkCursor) -> bool {
+        priority
+            .total_cmp(&self.priority)
+            .then_with(|| self.id.cmp(id))
+            .is_gt()
+    }
+}
+
+impl PartialEq for WorkSummary {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for WorkSummary {}
+
+impl PartialOrd for WorkSummary {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for WorkSummary {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .total_cmp(&self.priority)
+            .then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+/// The `ranked_at`, `priority` and `id` of the last request of the previous page
+type WorkCursor = (i64, f64, String);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkFilters {
+    /// In meters
+    max_distance: Option<f64>,
+    /// In milliseconds
+    max_age: Option<i64>,
+}
+
 fn request_work(
     user: Archived<User>,
+    page: Page<WorkCursor>,
+    filters: WorkFilters,
     help_requests: &HelpRequestDB,
-    user_db: &UserDB,
+    volunteer_profiles: &VolunteerProfileDB,
+    request_index: &RequestIndex,
 ) -> Result<Body, Error> {
     let coords = user.location;
+    let profile = volunteer_profiles.get(&user.username)?;
+    let limit = page.limit();
+    let now = Utc::now().timestamp_millis();
+    let ranked_at = page
+        .cursor
+        .as_ref()
+        .map_or(now, |(ranked_at, _, _)| *ranked_at);
+    let oldest = filters.max_age.map(|max_age| now - max_age);
+    let max_distance = match (
+        filters.max_distance,
+        profile
+            .as_ref()
+            .and_then(|profile| profile.max_distance.as_ref()),
+    ) {
+        (Some(filter), Some(profile)) => Some(filter.min(*profile)),
+        (filter, profile) => filter.or(profile.copied()),
+    };
+
+    let accepted = match &user.user_type {
+        ArchivedUserType::Volunteer(accepted) => accepted
+            .iter()
+            .map(|id| id.as_str())
+            .collect::<HashSet<_>>(),
+        _ => HashSet::new(),
+    };
+
+    // Only the `limit` highest priority requests past the cursor are kept, so memory use doesn't grow with the number of requests
+    let mut best = BinaryHeap::<WorkSummary>::with_capacity(limit + 1);
+
+    request_index.with_nearest(coords, |nearest| {
+        for (id, approximate_dist) in nearest {
+            // Distances are measured to the middle of the request's grid square, which can be closer than the request
+            let min_dist = approximate_dist / DISTANCE_TOLERANCE - MAX_APPROXIMATION_ERROR;
+
+            // Everything left is farther away than allowed, or too far away to outrank what's been found
+            if matches!(max_distance, Some(max) if min_dist > max)
+                || (best.len() == limit
+                    && matches!(best.peek(), Some(worst) if best_possible_priority(min_dist) < worst.priority))
+            {
+                break;
+            }
+
+            if accepted.contains(id) {
+                continue;
+            }
 
-    let mut requests = help_requests
-        .iter()
-        .map(|maybe_request| {
-            let (id, request) = maybe_request?;
+            let request = match help_requests.get(id)? {
+                Some(v) => v,
+                None => continue,
+            };
 
-            Ok((request.distance_meters(coords, user_db)?, id))
-        })
-        .filter(|v| match v {
-            Ok((dist, _)) => !dist.is_nan(),
-            Err(_) => true,
-        })
-        .collect::<Result<Vec<_>, Error>>()?;
+            if !request.state.is_open(request.volunteers_needed)
+                || matches!(&profile, Some(profile) if !profile.can_do(&request))
+                || matches!(oldest, Some(oldest) if request.creation_time < oldest)
+            {
+                continue;
+            }
 
-    requests.sort_unstable_by(|a, b| a.0.partial_cmp(&b.0).expect("NaN values were filtered"));
+            // Rounded everywhere the volunteer could see it or work it out from what they're shown
+            let dist = approximate_distance_to(coords, request.location);
 
-    Ok(Body::from(serde_json::to_string(
-        match requests.get(0..100) {
-            Some(v) => v,
-            None => &requests,
-        },
-    )?))
+            if dist.is_nan()
+                || matches!(max_distance, Some(max) if dist > max)
+                || matches!(&profile, Some(profile) if !profile.can_reach(dist, request.work_span(), now))
+            {
+                continue;
+            }
+
+            let summary = WorkSummary {
+                id: id.to_owned(),
+                priority: priority(
+                    dist,
+                    ranked_at - request.creation_time,
+                    &request.urgency,
+                    request.unhelped_before,
+                ),
+                ranked_at,
+                dist,
+                creation_time: request.creation_time,
+                notes_preview: request.notes.chars().take(NOTES_PREVIEW_LENGTH).collect(),
+                volunteers_needed: request.volunteers_needed,
+                volunteers_accepted: request.state.volunteer_count(),
+                window: request.window.as_ref().copied(),
+                task: request.task,
+                effort: request.effort,
+                equipment: request.equipment.to_vec(),
+                urgency: request.urgency.to_vec(),
+            };
+
+            if matches!(&page.cursor, Some(cursor) if !summary.is_after(cursor)) {
+                continue;
+            }
+
+            best.push(summary);
+
+            if best.len() > limit {
+                best.pop();
+            }
+        }
+
+        Ok::<_, Error>(())
+    })?;
+
+    Ok(Body::from(serde_json::to_string(&best.into_sorted_vec())?))
 }
 
 #[derive(Deserialize)]
@@ -149,6 +533,7 @@ fn get_request(
     user: Archived<User>,
     user_db: &UserDB,
     help_requests: &HelpRequestDB,
+    address_accesses: &AddressAccessDB,
 ) -> Result<Body, Error> {
     match help_requests.get(&id)? {
         Some(request) => {
@@ -161,17 +546,45 @@ fn get_request(
                 }
             };
 
-            let dist = distance_meters(user.location, senior.location);
+            // Only volunteers who are going to help need to know exactly where
+            let exact = request.state.is_helped_by(&user.username);
+
+            let (dist, address, location) = if exact {
+                log_address_access(address_accesses, &user.username, &senior.username, &id)?;
+
+                (
+                    request.distance_meters(user.location),
+                    senior.address.to_json(),
+                    request.location,
+                )
+            } else {
+                (
+                    approximate_distance_to(user.location, request.location),
+                    senior.address.area_to_json(),
+                    approximate_location(request.location),
+                )
+            };
 
             Ok(Body::from(serde_json::to_string(&json!({
                 "user": {
                     "username": &*senior.username,
                     "name": &*senior.name,
                 },
-                "picture": &*request.picture,
+                "pictures": hashes(&request.pictures),
                 "notes": &*request.notes,
                 "dist": dist,
-                "address": &*senior.address,
+                "address": address,
+                "location": <(f64, f64)>::from(location),
+                "exact": exact,
+                "state": request.state.to_json(),
+                "volunteersNeeded": request.volunteers_needed,
+                "window": request.window.as_ref(),
+                "recurringRequest": request.recurring_request.as_ref().map(|id| id.as_str()),
+                "task": request.task,
+                "effort": request.effort,
+                "equipment": &*request.equipment,
+                "urgency": &*request.urgency,
+                "afterPictures": hashes(&request.after_pictures),
             }))?))
         }
         None => Err(Error::RequestDoesntExist),
@@ -182,6 +595,10 @@ fn accept_request(
     bytes: &Bytes,
     user_db: &UserDB,
     help_requests: &HelpRequestDB,
+    dispatcher: &Dispatcher,
+    events: &EventBus,
+    notifier: &Notifier,
+    webhooks: &Webhooks,
 ) -> Result<Body, Error> {
     let username = authorize(bytes)?;
 
@@ -189,8 +606,8 @@ fn accept_request(
 
     debug!("{username} is accepting a request");
 
-    (user_db, help_requests)
-        .transaction(move |(user_db, requests_db)| {
+    let (help_request, name) = (user_db, help_requests, webhooks.deliveries()).transaction(
+        |(user_db, requests_db, deliveries_db)| {
             let mut user = user_db
                 .get(&username)?
                 .ok_or_else(|| Error::msg("There exists a token for a user that doesn't exist"))?
@@ -207,7 +624,10 @@ fn accept_request(
             }
             .to_original();
 
-            help_request.state = HelpRequestState::AcceptedBy(username.to_owned());
+            help_request.transition(Transition::Accept {
+                volunteer: &username,
+                volunteers_needed: help_request.volunteers_needed,
+            })?;
 
             requests_db.add(&id, &help_request)?;
 
@@ -217,44 +637,374 @@ fn accept_request(
 
             user_db.add(&username, &user)?;
 
-            Ok(Body::from("{}"))
-        })
-        .map_err(|e| e.into())
+            webhooks.request_event(
+                &user_db,
+                &requests_db,
+                &deliveries_db,
+                WebhookEvent::Accepted,
+                &id,
+            )?;
+
+            Ok((help_request, user.name))
+        },
+    )?;
+
+    events.request_updated(&id, &help_request, &[]);
+
+    if let Err(e) = notifier.notify(&help_request.username, Notification::accepted(&id, &name)) {
+        warn!("Failed to tell a senior their request was accepted: {e:?}");
+    }
+
+    // Either the volunteer took their offer, or someone else did and it can go to the next volunteer if there's still room
+    if let Err(e) = dispatcher.dispatch(&id) {
+        warn!("Failed to update the offer of an accepted request: {e:?}");
+    }
+
+    Ok(Body::from("{}"))
+}
+
+fn withdraw_from_request(
+    bytes: &Bytes,
+    user_db: &UserDB,
+    help_requests: &HelpRequestDB,
+    dispatcher: &Dispatcher,
+    events: &EventBus,
+    notifier: &Notifier,
+) -> Result<Body, Error> {
+    let username = authorize(bytes)?;
+
+    let id = extract_json::<GetRequestData>(bytes)?.id;
+
+    debug!("{username} is withdrawing from a request");
+
+    let (help_request, name) = (user_db, help_requests).transaction(|(user_db, requests_db)| {
+        let mut user = user_db
+            .get(&username)?
+            .ok_or_else(|| Error::msg("There exists a token for a user that doesn't exist"))?
+            .to_original();
+
+        let mut accepted = match user.user_type {
+            UserType::Volunteer(accepted) => accepted,
+            _ => return Err(Error::NotVolunteer.into()),
+        };
+
+        let mut help_request = match requests_db.get(&id)? {
+            Some(v) => v,
+            None => return Err(Error::RequestDoesntExist.into()),
+        }
+        .to_original();
+
+        help_request.transition(Transition::Withdraw(&username))?;
+
+        requests_db.add(&id, &help_request)?;
+
+        accepted.retain(|accepted_id| accepted_id != &id);
+
+        user.user_type = UserType::Volunteer(accepted);
+
+        user_db.add(&username, &user)?;
+
+        Ok((help_request, user.name))
+    })?;
+
+    events.request_updated(&id, &help_request, std::slice::from_ref(&username));
+
+    if let Err(e) = notifier.notify(&help_request.username, Notification::withdrawn(&id, &name)) {
+        warn!("Failed to tell a senior a volunteer withdrew from their request: {e:?}");
+    }
+
+    if let Err(e) = dispatcher.withdrawn(&id, &username) {
+        warn!("Failed to offer a request someone withdrew from to another volunteer: {e:?}");
+    }
+
+    Ok(Body::from("{}"))
+}
+
+/// The open requests offered to the volunteer that they haven't answered yet
+fn dispatch_offers(
+    username: String,
+    user: Archived<User>,
+    help_requests: &HelpRequestDB,
+    dispatcher: &Dispatcher,
+) -> Result<Body, Error> {
+    let now = Utc::now().timestamp_millis();
+    let mut offers = Vec::new();
+
+    for maybe_dispatch in dispatcher.dispatches().iter() {
+        let (id, dispatch) = maybe_dispatch?;
+
+        if !dispatch.is_offered_to(&username, now) {
+            continue;
+        }
+
+        let request = match help_requests.get(&id)? {
+            Some(v) if v.state.is_open(v.volunteers_needed) => v,
+            _ => continue,
+        };
+
+        offers.push(json!({
+            "id": id,
+            "expires": dispatch.expires(),
+            "dist": approximate_distance_to(user.location, request.location),
+            "notesPreview": request.notes.chars().take(NOTES_PREVIEW_LENGTH).collect::<String>(),
+            "window": request.window.as_ref(),
+            "task": request.task,
+            "effort": request.effort,
+            "equipment": &*request.equipment,
+            "urgency": &*request.urgency,
+        }));
+    }
+
+    Ok(Body::from(serde_json::to_string(&offers)?))
 }
 
-fn accepted_requests(user: Archived<User>) -> Result<Body, Error> {
+fn accepted_requests(user: Archived<User>, page: Page<String>) -> Result<Body, Error> {
     match &user.user_type {
-        ArchivedUserType::Volunteer(accepted) => Ok(Body::from(serde_json::to_string::<
-            Vec<String>,
-        >(&accepted.deserialize())?)),
+        ArchivedUserType::Volunteer(accepted) => {
+            let start = match &page.cursor {
+                Some(cursor) => accepted
+                    .iter()
+                    .position(|id| id.as_str() == cursor)
+                    .map_or(accepted.len(), |i| i + 1),
+                None => 0,
+            };
+
+            Ok(Body::from(serde_json::to_string(
+                &accepted
+                    .iter()
+                    .skip(start)
+                    .take(page.limit())
+                    .map(|id| id.as_str())
+                    .collect::<Vec<_>>(),
+            )?))
+        }
         _ => Err(Error::Anyhow(anyhow::Error::msg(
             "The user isn't a volunteer, this case should've been filtered earlier",
         ))),
     }
 }
 
-fn marking_as_completed(
+#[derive(Deserialize)]
+struct RoutePlanData {
+    /// Where to start from instead of the volunteer's address
+    location: Option<(f64, f64)>,
+}
+
+/// The volunteer's unfinished requests in the order that takes the least travel, with when they'd get to and finish each one if they left now.
+fn route_plan(
     username: String,
+    user: Archived<User>,
+    data: RoutePlanData,
+    help_requests: &HelpRequestDB,
+    volunteer_profiles: &VolunteerProfileDB,
+) -> Result<Body, Error> {
+    let start = data.location.map_or(user.location, Location::from);
+    let travel_mode = volunteer_profiles
+        .get(&username)?
+        .map_or_else(TravelMode::default, |profile| profile.travel_mode);
+
+    let accepted = match &user.user_type {
+        ArchivedUserType::Volunteer(accepted) => accepted,
+        _ => {
+            return Err(Error::msg(
+                "The user isn't a volunteer, this case should've been filtered earlier",
+            ))
+        }
+    };
+
+    let mut stops = Vec::new();
+
+    for id in accepted.iter() {
+        if let Some(request) = help_requests.get(id)? {
+            if request.state.is_accepted_by(&username) {
+                stops.push((id.as_str(), request));
+            }
+        }
+    }
+
+    let order = plan_route(
+        start,
+        &stops
+            .iter()
+            .map(|(_, request)| request.location)
+            .collect::<Vec<_>>(),
+    );
+
+    let mut at = start;
+    let mut time = Utc::now().timestamp_millis();
+    let mut total_distance = 0.;
+    let mut visits = Vec::with_capacity(order.len());
+
+    for i in order {
+        let (id, request) = &stops[i];
+
+        let dist = distance_meters(at, request.location);
+        let travel_time = travel_mode.travel_time(dist);
+        let arrival = time + travel_time;
+        // Work can't start before the senior's window does
+        let work_start = match request.window.as_ref() {
+            Some(window) => arrival.max(window.start),
+            None => arrival,
+        };
+        let departure = work_start + request.effort.estimated_time();
+
+        visits.push(json!({
+            "id": id,
+            "dist": dist,
+            "travelTime": travel_time,
+            "arrival": arrival,
+            "workStart": work_start,
+            "departure": departure,
+            "window": request.window.as_ref(),
+        }));
+
+        at = request.location;
+        time = departure;
+        total_distance += dist;
+    }
+
+    Ok(Body::from(serde_json::to_string(&json!({
+        "stops": visits,
+        "totalDistance": total_distance,
+        "finishTime": time,
+    }))?))
+}
+
+#[derive(Deserialize)]
+struct MarkCompletedData {
     id: String,
+    /// The hashes of uploaded pictures of the finished work
+    #[serde(default)]
+    pictures: Vec<String>,
+}
+
+fn marking_as_completed(
+    username: String,
+    name: &str,
+    data: MarkCompletedData,
     help_requests: &HelpRequestDB,
+    picture_store: &PictureStore,
+    events: &EventBus,
+    notifier: &Notifier,
+) -> Result<Body, Error> {
+    if *REQUIRE_AFTER_PICTURE && data.pictures.is_empty() {
+        return Err(Error::NoAfterPicture);
+    }
+
+    picture_store.check_all(&data.pictures, &username)?;
+
+    let request = help_requests.transaction(|requests_db| {
+        let mut request = match requests_db.get(&data.id)? {
+            Some(v) => v.to_original(),
+            None => return Err(Error::RequestDoesntExist.into()),
+        };
+
+        request.transition(Transition::MarkCompleted(&username))?;
+
+        // Marking it completed again after a dispute replaces the old pictures
+        request.after_pictures = data.pictures.to_owned();
+
+        requests_db.add(&data.id, &request)?;
+
+        Ok(request)
+    })?;
+
+    events.request_updated(&data.id, &request, &[]);
+
+    if let Err(e) = notifier.notify(
+        &request.username,
+        Notification::marked_completed(&data.id, name),
+    ) {
+        warn!("Failed to tell a senior their request was marked as completed: {e:?}");
+    }
+
+    Ok(Body::from("{}"))
+}
+
+/// Makes the volunteer accept every help request made from the recurring request from now on.
+fn subscribe_to_recurring_request(
+    username: String,
+    id: String,
+    recurring_requests: &RecurringRequestDB,
 ) -> Result<Body, Error> {
-    help_requests
-        .transaction(|requests_db| {
-            let mut request = match requests_db.get(&id)? {
+    recurring_requests
+        .transaction(|recurring_db| {
+            let mut recurring = match recurring_db.get(&id)? {
                 Some(v) => v.to_original(),
-                None => return Err(Error::RequestDoesntExist.into()),
+                None => return Err(Error::RecurringRequestDoesntExist.into()),
             };
 
-            match request.state {
-                HelpRequestState::AcceptedBy(accepted_by) if username == accepted_by => {
-                    request.state = HelpRequestState::MarkedCompletedBy(accepted_by);
-                }
-                _ => return Err(Error::RequestNotAcceptedByUser.into()),
+            if recurring.subscribers.contains(&username) {
+                return Ok(Body::from("{}"));
+            }
+
+            if recurring.subscribers.len() >= recurring.volunteers_needed as usize {
+                return Err(Error::RecurringRequestFull.into());
             }
 
-            requests_db.add(&id, &request)?;
+            recurring.subscribers.push(username.to_owned());
+
+            recurring_db.add(&id, &recurring)?;
 
             Ok(Body::from("{}"))
         })
         .map_err(|e| e.into())
 }
+
+fn unsubscribe_from_recurring_request(
+    username: String,
+    id: String,
+    recurring_requests: &RecurringRequestDB,
+) -> Result<Body, Error> {
+    recurring_requests
+        .transaction(|recurring_db| {
+            let mut recurring = match recurring_db.get(&id)? {
+                Some(v) => v.to_original(),
+                None => return Err(Error::RecurringRequestDoesntExist.into()),
+            };
+
+            recurring
+                .subscribers
+                .retain(|subscriber| subscriber != &username);
+
+            recurring_db.add(&id, &recurring)?;
+
+            Ok(Body::from("{}"))
+        })
+        .map_err(|e| e.into())
+}
+
+fn get_volunteer_profile(
+    username: String,
+    volunteer_profiles: &VolunteerProfileDB,
+) -> Result<Body, Error> {
+    let profile = volunteer_profiles
+        .get(&username)?
+        .map(|profile| profile.to_original());
+
+    Ok(Body::from(serde_json::to_string(&profile)?))
+}
+
+fn set_volunteer_profile(
+    username: String,
+    profile: VolunteerProfile,
+    volunteer_profiles: &VolunteerProfileDB,
+) -> Result<Body, Error> {
+    if matches!(profile.max_distance, Some(max) if max.is_nan() || max < 0.) {
+        return Err(Error::InvalidProfile(
+            "The farthest distance can't be negative",
+        ));
+    }
+
+    for window in &profile.availability {
+        window.validate()?;
+    }
+
+    volunteer_profiles.transaction(|profiles_db| {
+        profiles_db.add(&username, &profile)?;
+
+        Ok(())
+    })?;
+
+    Ok(Body::from("{}"))
+}
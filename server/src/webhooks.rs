@@ -0,0 +1,330 @@
+//! Posts help requests to partner organizations like churches and city services when they're made, accepted and completed, so they can keep them in their own systems. The webhooks are configured by whoever runs the server in the JSON file set with `WEBHOOKS_PATH`, as an array of `{ url, secret, events }`. Every delivery is kept in a log, and failed ones are tried again later with longer and longer waits.
+//!
+//! Each delivery is signed with HMAC-SHA3-256 using the webhook's secret, over the `X-Shovelmates-Timestamp` header, a `.`, and the body, and the signature is sent in hex in the `X-Shovelmates-Signature` header.
+
+use std::{env, fs, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{debug, info, trace, warn};
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha3::Sha3_256;
+use sled::transaction::ConflictableTransactionError;
+use ulid::Ulid;
+
+use crate::{
+    db::{Db, Transaction, Transactional},
+    errors::Error,
+    pictures::hashes,
+    privacy::approximate_location,
+    HelpRequest, User,
+};
+
+/// How long to wait for a partner's server before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the delivery log is checked for deliveries to make
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Deliveries that still fail after this many tries are given up on
+const MAX_ATTEMPTS: u32 = 10;
+
+/// How long to wait before trying a delivery again for the first time. The wait doubles with every failure.
+const RETRY_DELAY: i64 = 60 * 1000;
+
+/// How long finished deliveries are kept in the log
+const LOG_RETENTION: i64 = 30 * 24 * 60 * 60 * 1000;
+
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Debug,
+    Serialize,
+    Deserialize,
+    Archive,
+    RkyvSerialize,
+    RkyvDeserialize,
+)]
+#[archive(as = "Self")]
+#[repr(u8)]
+pub enum WebhookEvent {
+    Created,
+    Accepted,
+    Completed,
+}
+
+/// One of the webhooks in the `WEBHOOKS_PATH` file
+#[derive(Deserialize)]
+struct Webhook {
+    url: String,
+    secret: Secret<String>,
+    events: Vec<WebhookEvent>,
+}
+
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub enum DeliveryStatus {
+    Pending,
+    /// The HTTP status code the partner responded with
+    Delivered(u16),
+    GaveUp,
+}
+
+/// A delivery of an event to one webhook, keyed by a ULID so the oldest are made first
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+#[repr(C)]
+pub struct WebhookDelivery {
+    url: String,
+    event: WebhookEvent,
+    /// The JSON that's posted
+    payload: String,
+    status: DeliveryStatus,
+    attempts: u32,
+    /// When it can be tried again, or when it finished, in milliseconds since the UNIX epoch
+    next_attempt: i64,
+    /// Why the last try failed
+    last_error: Option<String>,
+}
+
+pub type WebhookDeliveryDB = Db<1000, WebhookDelivery>;
+
+#[derive(Clone)]
+pub struct Webhooks {
+    webhooks: Arc<Vec<Webhook>>,
+    deliveries: WebhookDeliveryDB,
+    agent: ureq::Agent,
+}
+
+impl Webhooks {
+    pub fn from_env(deliveries: &WebhookDeliveryDB) -> Webhooks {
+        let webhooks = match env::var("WEBHOOKS_PATH") {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path).expect("the webhooks file to be readable");
+                let webhooks: Vec<Webhook> = serde_json::from_str(&contents)
+                    .expect("the webhooks file to be an array of `{ url, secret, events }`");
+
+                info!("Posting help requests to {} webhooks", webhooks.len());
+
+                webhooks
+            }
+            Err(_) => Vec::new(),
+        };
+
+        Webhooks {
+            webhooks: Arc::new(webhooks),
+            deliveries: deliveries.to_owned(),
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+        }
+    }
+
+    pub fn deliveries(&self) -> &WebhookDeliveryDB {
+        &self.deliveries
+    }
+
+    /// Puts a delivery of the request as it is in the transaction in the log for every webhook that wants the event. It's queued in the transaction that changes the request, so partners hear about exactly the changes that are made.
+    pub fn request_event(
+        &self,
+        users_db: &Transaction<250, User>,
+        requests_db: &Transaction<150, HelpRequest>,
+        deliveries_db: &Transaction<1000, WebhookDelivery>,
+        event: WebhookEvent,
+        id: &str,
+    ) -> Result<(), ConflictableTransactionError<Error>> {
+        let urls = self
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.events.contains(&event))
+            .map(|webhook| webhook.url.to_owned())
+            .collect::<Vec<_>>();
+
+        if urls.is_empty() {
+            return Ok(());
+        }
+
+        let request = requests_db.get(id)?.ok_or(Error::RequestDoesntExist)?;
+        let senior = users_db.get(&request.username)?.ok_or_else(|| {
+            Error::msg("The senior of a help request doesn't exist in the database")
+        })?;
+
+        let now = Utc::now().timestamp_millis();
+
+        // Partners are only told roughly where the senior is, like volunteers who haven't accepted the request
+        let payload = json!({
+            "event": event,
+            "time": now,
+            "request": {
+                "id": id,
+                "creationTime": request.creation_time,
+                "pictures": hashes(&request.pictures),
+                "notes": &*request.notes,
+                "area": senior.address.area_to_json(),
+                "location": <(f64, f64)>::from(approximate_location(request.location)),
+                "state": request.state.to_json(),
+                "volunteersNeeded": request.volunteers_needed,
+                "window": request.window.as_ref(),
+                "task": request.task,
+                "effort": request.effort,
+                "equipment": &*request.equipment,
+                "urgency": &*request.urgency,
+                "afterPictures": hashes(&request.after_pictures),
+            },
+        })
+        .to_string();
+
+        for url in &urls {
+            deliveries_db.add(
+                &Ulid::from_parts(now as u64, rand::random()).to_string(),
+                &WebhookDelivery {
+                    url: url.to_owned(),
+                    event,
+                    payload: payload.to_owned(),
+                    status: DeliveryStatus::Pending,
+                    attempts: 0,
+                    next_attempt: now,
+                    last_error: None,
+                },
+            )?;
+        }
+
+        debug!("Queued {} webhook deliveries of request {id}", urls.len());
+
+        Ok(())
+    }
+
+    /// Posts the payload to the webhook, returning the status code it responded with
+    fn post(
+        &self,
+        webhook: &Webhook,
+        id: &str,
+        event: WebhookEvent,
+        payload: &str,
+    ) -> Result<u16, String> {
+        let timestamp = Utc::now().timestamp().to_string();
+
+        let mut mac = Hmac::<Sha3_256>::new_from_slice(webhook.secret.expose_secret().as_bytes())
+            .map_err(|e| e.to_string())?;
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+
+        let response = self
+            .agent
+            .post(&webhook.url)
+            .set("Content-Type", "application/json")
+            .set("X-Shovelmates-Event", &format!("{event:?}"))
+            .set("X-Shovelmates-Delivery", id)
+            .set("X-Shovelmates-Timestamp", &timestamp)
+            .set(
+                "X-Shovelmates-Signature",
+                &format!("{:x}", mac.finalize().into_bytes()),
+            )
+            .send_string(payload)
+            .map_err(|e| e.to_string())?;
+
+        Ok(response.status())
+    }
+
+    /// Makes every delivery that's due, and forgets finished ones that are old enough. Returns how many were delivered.
+    fn deliver_due(&self) -> Result<usize, Error> {
+        let now = Utc::now().timestamp_millis();
+        let mut delivered = 0;
+
+        for maybe_delivery in self.deliveries.iter() {
+            let (id, delivery) = maybe_delivery?;
+
+            if !matches!(delivery.status, ArchivedDeliveryStatus::Pending) {
+                if delivery.next_attempt + LOG_RETENTION < now {
+                    self.deliveries.transaction(|deliveries_db| {
+                        deliveries_db.delete(&id)?;
+                        Ok(())
+                    })?;
+                }
+
+                continue;
+            }
+
+            if delivery.next_attempt > now {
+                continue;
+            }
+
+            let webhook = self
+                .webhooks
+                .iter()
+                .find(|webhook| webhook.url == delivery.url.as_str());
+
+            let result = match webhook {
+                Some(webhook) => self.post(webhook, &id, delivery.event, &delivery.payload),
+                None => Err("The webhook isn't configured anymore".to_owned()),
+            };
+
+            let mut delivery = delivery.to_original();
+
+            delivery.attempts += 1;
+
+            match &result {
+                Ok(status) => {
+                    delivery.status = DeliveryStatus::Delivered(*status);
+                    delivery.next_attempt = now;
+                    delivery.last_error = None;
+                }
+                // A webhook that was removed won't come back by waiting
+                Err(e) if webhook.is_none() || delivery.attempts >= MAX_ATTEMPTS => {
+                    delivery.status = DeliveryStatus::GaveUp;
+                    delivery.next_attempt = now;
+                    delivery.last_error = Some(e.to_owned());
+                }
+                Err(e) => {
+                    delivery.next_attempt = now + RETRY_DELAY * 2_i64.pow(delivery.attempts - 1);
+                    delivery.last_error = Some(e.to_owned());
+                }
+            }
+
+            self.deliveries.transaction(|deliveries_db| {
+                deliveries_db.add(&id, &delivery)?;
+                Ok(())
+            })?;
+
+            match (&result, &delivery.status) {
+                (Ok(status), _) => {
+                    info!(
+                        "Delivered {id} to {} and it responded with {status}",
+                        delivery.url
+                    );
+                    delivered += 1;
+                }
+                (Err(e), DeliveryStatus::GaveUp) => {
+                    warn!("Gave up on delivering {id} to {}: {e}", delivery.url)
+                }
+                (Err(e), _) => warn!(
+                    "Failed to deliver {id} to {}, trying again later: {e}",
+                    delivery.url
+                ),
+            }
+        }
+
+        Ok(delivered)
+    }
+}
+
+/// Periodically makes the webhook deliveries that are due.
+pub async fn run_webhooks(webhooks: Webhooks) {
+    let mut interval = tokio::time::interval(DELIVERY_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let webhooks = webhooks.to_owned();
+
+        match tokio::task::spawn_blocking(move || webhooks.deliver_due()).await {
+            Ok(Ok(0)) => trace!("No webhook deliveries were made"),
+            Ok(Ok(delivered)) => info!("Made {delivered} webhook deliveries"),
+            Ok(Err(e)) => warn!("Failed to make webhook deliveries: {e:?}"),
+            Err(e) => warn!("Failed to make webhook deliveries: {e}"),
+        }
+    }
+}
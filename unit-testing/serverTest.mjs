@@ -1,5 +1,5 @@
 import { readFile, rm } from 'fs/promises'
-import { argv, stdout } from 'process'
+import { argv, env, stdout } from 'process'
 import { spawn } from 'child_process'
 import { fileURLToPath } from 'url'
 import { dirname, join } from 'path'
@@ -51,25 +51,8 @@ const helpRequest = {
 const clearDB = async () => await rm(join(dirname(fileURLToPath(import.meta.url),""),"../server/db"),{ recursive: true, force: true })
 
 
-const stateList = {"AL":"Alabama","AK":"Alaska","AZ":"Arizona","AR":"Arkansas","CA":"California","CO":"Colorado","CT":"Connecticut","DE":"Delaware","FL":"Florida","GA":"Georgia","HI":"Hawaii","ID":"Idaho","IL":"Illinois","IN":"Indiana","IA":"Iowa","KS":"Kansas","KY":"Kentucky","LA":"Louisiana","ME":"Maine","MD":"Maryland","MA":"Massachusetts","MI":"Michigan","MN":"Minnesota","MS":"Mississippi","MO":"Missouri","MT":"Montana","NE":"Nebraska","NV":"Nevada","NH":"New Hampshire","NJ":"New Jersey","NM":"New Mexico","NY":"New York","NC":"North Carolina","ND":"North Dakota","OH":"Ohio","OK":"Oklahoma","OR":"Oregon","PA":"Pennsylvania","RI":"Rhode Island","SC":"South Carolina","SD":"South Dakota","TN":"Tennessee","TX":"Texas","UT":"Utah","VT":"Vermont","VA":"Virginia","WA":"Washington","WV":"West Virginia","WI":"Wisconsin","WY":"Wyoming"}
 // start of api implementation
 let authorizationString;
-const addressToLonLat = async (address) => {
-  const res = await fetch(
-    `https://nominatim.openstreetmap.org/search?addressdetails=1&q=${address.line1}, ${address.city}, ${stateList[address.state]}&format=jsonv2&countrycodes=us&limit=1`, 
-    {
-    method: "GET",
-    headers: {
-      'Content-Type': 'application/x-www-form-urlencoded',
-    }
-  })
-  if (!res.ok) throw "Bad Request"
-  const json = await res.json()
-  const queryData = json?.[0]
-  const lonLat = [queryData?.lat, queryData?.lon]
-  if (!lonLat[0] || !lonLat[1]) throw "Uh oh. Address request didn't yield a lon lat....";
-  return lonLat.map(v=>Number(v));
-}
 const apiFetchPost = async (endpoint, body, statusErrors={}) => {
   const res = await fetch(`${serverURL}/api/${endpoint}`, {
     method: "POST",
@@ -86,20 +69,11 @@ const apiFetchPost = async (endpoint, body, statusErrors={}) => {
   }
   return {...res, text:()=>outText, json:()=>{try {return JSON.parse(outText)} catch {throw "Invalid JSON Server Response"}}};
 }
-const concatAddress = async (address) => {
-  return `${address.line1?.trim()}
-${address.line2?.trim()}
-${address.city?.trim()} ${address.state?.trim()} ${address.zip?.trim()}
-United States of America`
-}
 const createAccount = async (user) => {
-  const location = await addressToLonLat(user.address)
-  const addressString = await concatAddress(user.address)
   const res = await apiFetchPost("create-account",{
       username: user.username, 
       name: user.name,
-      address: addressString,
-      location,
+      address: user.address,
       userType: user.userType,
       password: user.password
   }, {
@@ -123,8 +97,16 @@ const getUserData = async () => {
   const res = await apiFetchPost("user-data", {authorization: authorizationString})
   return res.json()
 }
+const uploadPicture = async (data, mimeType) => {
+  const res = await apiFetchPost("upload-picture", {authorization: authorizationString, mimeType, data}, {
+    '413': "Picture Too Large Error",
+    '415': "Unsupported Picture Error"
+  })
+  return res.json().hash
+}
 const requestHelp = async (helpRequest) => {
-  const res = await apiFetchPost("request-help", {authorization: authorizationString, ...helpRequest}, {
+  const pictures = [await uploadPicture(helpRequest.picture, "image/png")]
+  const res = await apiFetchPost("request-help", {authorization: authorizationString, pictures, notes: helpRequest.notes}, {
     '405': "Not Senior Error"
   })
   return res.json()
@@ -193,9 +175,12 @@ const test = async (testFunction, testName, flip = false) => {
 await clearDB()
 const serverProcess = spawn("cargo",["run"], {
   cwd: join(folderPathOfCurrentFile, "../server"),
-  // env: {
-  //   "RUST_LOG": "DEBUG"
-  // }
+  env: {
+    ...env,
+    // "RUST_LOG": "DEBUG",
+    "GEOCODER": "gazetteer",
+    "GAZETTEER_PATH": join(folderPathOfCurrentFile, "gazetteer.csv"),
+  }
 })
 const serverReady = () => {
   return new Promise((res, rej) => {
@@ -244,14 +229,14 @@ await test(async () => {
   const availableJobs = await requestWork()
   if (!availableJobs[0]) throw "No Work Request Found"
   // console.log(availableJobs[0])
-  for (const jobId of availableJobs) {
-    // console.log("Job ID:",jobId)
-    const workRequest = getWorkRequestByID(jobId[1])
-    if (!workRequest) throw `Work Request ID ${job} Was Not Found`
+  for (const job of availableJobs) {
+    // console.log("Job ID:",job.id)
+    const workRequest = getWorkRequestByID(job.id)
+    if (!workRequest) throw `Work Request ID ${job.id} Was Not Found`
     // if (!extraDebug) console.log(await workRequest)
-    const accepted = await acceptRequest(jobId[1])
+    const accepted = await acceptRequest(job.id)
     console.log(accepted)
-    const completed = await markRequestAsCompleted(jobId[1])
+    const completed = await markRequestAsCompleted(job.id)
     console.log(completed)
   }
   const allAccepted = await getAcceptedRequests()